@@ -0,0 +1,311 @@
+use crate::chip8::{Chip8, Chip8System};
+
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+/// What a byte of memory was found to be.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ByteKind {
+    /// Outside the rom and never reached as code.
+    Unknown,
+    /// The first byte of an instruction this many bytes long.
+    Code(u8),
+    /// A later byte of an instruction.
+    CodeTail,
+    /// Part of the rom that's never reached as code, eg sprites.
+    Data,
+}
+
+/// Why an address gets a label, in increasing order of priority.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LabelKind {
+    /// Pointed to by `i`.
+    Data,
+    /// Jumped to.
+    Jump,
+    /// Called.
+    Sub,
+    /// The start of the rom.
+    Main,
+}
+
+/// Code and data found by following jumps, calls and skips from the entry point.
+pub struct Analysis {
+    kinds: Vec<ByteKind>,
+    labels: BTreeMap<u16, LabelKind>,
+}
+
+/// Size of the instruction at `addr`, as the emulator would run it.
+pub fn ins_len(chip8: &Chip8, addr: u16) -> u16 {
+    let addr = addr as usize;
+    let op = ((chip8.mem[addr] as u16) << 8) | (chip8.mem[addr + 1] as u16);
+    match chip8.system {
+        Chip8System::XOCHIP if op == 0xf000 => 4,
+        Chip8System::MEGACHIP if op >> 8 == 0x01 => 4,
+        _ => 2,
+    }
+}
+
+fn is_skip(chip8: &Chip8, op: u16) -> bool {
+    match op >> 12 {
+        0x3 | 0x4 => true,
+        0x5 | 0x9 => op & 0xf == 0,
+        0xe => match op & 0xff {
+            0x9e | 0xa1 => true,
+            0xf2 | 0xf5 => chip8.system == Chip8System::CHIP8X,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+impl Analysis {
+    /// Nothing found yet.
+    pub fn empty() -> Self {
+        Self {
+            kinds: vec![],
+            labels: BTreeMap::new(),
+        }
+    }
+
+    /// Trace the code reachable from the entry point and `roots`, eg addresses seen
+    /// running. Earlier roots win where two traces disagree on an instruction's start.
+    pub fn new(chip8: &Chip8, roots: impl IntoIterator<Item = u16>) -> Self {
+        let end = Self::end(chip8);
+        let mut ret = Self {
+            kinds: vec![ByteKind::Unknown; end],
+            labels: BTreeMap::new(),
+        };
+
+        // Octo's `main`, where the rom starts even if boot code comes first
+        ret.add_label(chip8.start_address, LabelKind::Main);
+        let entry = chip8.entry_point();
+        ret.add_label(entry, LabelKind::Jump);
+        let mut todo: Vec<u16> = roots.into_iter().collect();
+        todo.push(entry);
+        // Popped from the back, so the first root is traced first
+        todo.reverse();
+
+        while let Some(addr) = todo.pop() {
+            ret.trace(chip8, addr, &mut todo);
+        }
+
+        let rom_start = chip8.start_address as usize;
+        let rom_end = (rom_start + chip8.rom_len).min(end);
+        for kind in &mut ret.kinds[rom_start.min(rom_end)..rom_end] {
+            if *kind == ByteKind::Unknown {
+                *kind = ByteKind::Data;
+            }
+        }
+        ret
+    }
+
+    /// Follow straight-line code from `addr`, queueing the other paths out of it.
+    fn trace(&mut self, chip8: &Chip8, mut addr: u16, todo: &mut Vec<u16>) {
+        loop {
+            let start = addr as usize;
+            if start + 2 > self.kinds.len() {
+                return;
+            }
+            let len = ins_len(chip8, addr) as usize;
+            // Stop at code already traced, or that doesn't fit
+            if start + len > self.kinds.len()
+                || self.kinds[start..start + len]
+                    .iter()
+                    .any(|kind| *kind != ByteKind::Unknown)
+            {
+                return;
+            }
+            self.kinds[start] = ByteKind::Code(len as u8);
+            for kind in &mut self.kinds[start + 1..start + len] {
+                *kind = ByteKind::CodeTail;
+            }
+
+            let op = ((chip8.mem[start] as u16) << 8) | (chip8.mem[start + 1] as u16);
+            let nnn = op & 0xfff;
+            let next = start + len;
+            match op >> 12 {
+                0x0 if op == 0x00ee => return,
+                0x0 if op == 0x00fd && chip8.system.has_schip() => return,
+                0x1 => {
+                    self.add_label(nnn, LabelKind::Jump);
+                    todo.push(nnn);
+                    return;
+                }
+                0x2 => {
+                    self.add_label(nnn, LabelKind::Sub);
+                    todo.push(nnn);
+                }
+                0xa => self.add_label(nnn, LabelKind::Data),
+                // The offset isn't known, but jump tables start at nnn
+                0xb if chip8.system != Chip8System::CHIP8X => {
+                    self.add_label(nnn, LabelKind::Jump);
+                    todo.push(nnn);
+                    return;
+                }
+                0xf if len == 4 => {
+                    let target =
+                        ((chip8.mem[start + 2] as u16) << 8) | (chip8.mem[start + 3] as u16);
+                    self.add_label(target, LabelKind::Data);
+                }
+                // Both the next instruction and the one after it are reached
+                _ if is_skip(chip8, op) && next + 2 <= self.kinds.len() => {
+                    let skipped = next + ins_len(chip8, next as u16) as usize;
+                    if skipped < self.kinds.len() {
+                        todo.push(skipped as u16);
+                    }
+                }
+                _ => (),
+            }
+            if next >= self.kinds.len() {
+                return;
+            }
+            addr = next as u16;
+        }
+    }
+
+    fn add_label(&mut self, addr: u16, kind: LabelKind) {
+        let label = self.labels.entry(addr).or_insert(kind);
+        *label = (*label).max(kind);
+    }
+
+    /// The end of the analysed memory. `pc` can't reach past 64K, so neither does code.
+    pub fn end(chip8: &Chip8) -> usize {
+        chip8.mem_size.min(chip8.mem.len()).min(0x10000)
+    }
+
+    pub fn kind(&self, addr: u16) -> ByteKind {
+        self.kinds
+            .get(addr as usize)
+            .copied()
+            .unwrap_or(ByteKind::Unknown)
+    }
+
+    /// The runs of memory traced as code, whose bytes are all the tracing depended on.
+    pub fn code_ranges(&self) -> Vec<Range<usize>> {
+        let mut ret: Vec<Range<usize>> = vec![];
+        for (addr, kind) in self.kinds.iter().enumerate() {
+            if !matches!(kind, ByteKind::Code(_) | ByteKind::CodeTail) {
+                continue;
+            }
+            match ret.last_mut() {
+                Some(range) if range.end == addr => range.end += 1,
+                _ => ret.push(addr..addr + 1),
+            }
+        }
+        ret
+    }
+
+    /// The label at `addr`, if something refers to it.
+    pub fn label(&self, addr: u16) -> Option<String> {
+        self.labels.get(&addr).map(|kind| match kind {
+            LabelKind::Main => String::from("main"),
+            LabelKind::Sub => format!("sub_{:03x}", addr),
+            LabelKind::Jump => format!("label_{:03x}", addr),
+            LabelKind::Data => format!("data_{:03x}", addr),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profiles::Profile;
+
+    fn analyse(system: Chip8System, rom: &[u8]) -> Analysis {
+        let mut chip8 = Chip8::new();
+        chip8.set_profile(&Profile::new());
+        chip8.system = system;
+        chip8.load_rom(rom.to_vec());
+        Analysis::new(&chip8, [])
+    }
+
+    #[test]
+    fn skips_reach_both_paths() {
+        #[rustfmt::skip]
+        let analysis = analyse(Chip8System::XOCHIP, &[
+            0x30, 0x00, // 200: if v0 != 0 then
+            0x12, 0x08, // 202: jump 208
+            0x00, 0xe0, // 204: clear
+            0x00, 0xee, // 206: return
+            0x00, 0xee, // 208: return
+            0xaa, 0xbb, // 20a: data
+        ]);
+        for addr in (0x200..0x20a).step_by(2) {
+            assert!(analysis.kind(addr) == ByteKind::Code(2), "{:03x}", addr);
+        }
+        assert!(analysis.kind(0x20a) == ByteKind::Data);
+        assert!(analysis.kind(0x20c) == ByteKind::Unknown);
+        assert_eq!(analysis.label(0x200).as_deref(), Some("main"));
+        assert_eq!(analysis.label(0x208).as_deref(), Some("label_208"));
+        assert_eq!(analysis.label(0x204), None);
+    }
+
+    #[test]
+    fn skips_over_long_instructions() {
+        #[rustfmt::skip]
+        let analysis = analyse(Chip8System::XOCHIP, &[
+            0x30, 0x00,             // 200: if v0 != 0 then
+            0xf0, 0x00, 0x03, 0x00, // 202: i := long 0x300
+            0x00, 0xee,             // 206: return
+        ]);
+        assert!(analysis.kind(0x202) == ByteKind::Code(4));
+        assert!(analysis.kind(0x204) == ByteKind::CodeTail);
+        assert!(analysis.kind(0x206) == ByteKind::Code(2));
+        assert_eq!(analysis.label(0x300).as_deref(), Some("data_300"));
+        let ranges = analysis.code_ranges();
+        assert_eq!(ranges.len(), 1);
+        assert_eq!(ranges[0], 0x200..0x208);
+    }
+
+    #[test]
+    fn jump0_traces_its_table() {
+        #[rustfmt::skip]
+        let analysis = analyse(Chip8System::XOCHIP, &[
+            0xb2, 0x04, // 200: jump0 204
+            0xaa, 0xbb, // 202: data
+            0x12, 0x08, // 204: jump 208
+            0x12, 0x08, // 206: jump 208
+            0x00, 0xee, // 208: return
+        ]);
+        assert_eq!(analysis.label(0x204).as_deref(), Some("label_204"));
+        assert!(analysis.kind(0x202) == ByteKind::Data);
+        assert!(analysis.kind(0x204) == ByteKind::Code(2));
+        // Only the start of the table is known
+        assert!(analysis.kind(0x206) == ByteKind::Data);
+        assert!(analysis.kind(0x208) == ByteKind::Code(2));
+    }
+
+    #[test]
+    fn megachip_long_instructions() {
+        #[rustfmt::skip]
+        let rom = [
+            0x01, 0x00, 0x03, 0x00, // 200: ldhi 0x000300
+            0xf0, 0x00,             // 204: not long outside XO-CHIP
+            0x00, 0xee,             // 206: return
+        ];
+        let analysis = analyse(Chip8System::MEGACHIP, &rom);
+        assert!(analysis.kind(0x200) == ByteKind::Code(4));
+        assert!(analysis.kind(0x202) == ByteKind::CodeTail);
+        assert!(analysis.kind(0x204) == ByteKind::Code(2));
+        assert!(analysis.kind(0x206) == ByteKind::Code(2));
+
+        // Elsewhere 01nn is a 2-byte instruction
+        let analysis = analyse(Chip8System::XOCHIP, &rom);
+        assert!(analysis.kind(0x200) == ByteKind::Code(2));
+        assert!(analysis.kind(0x202) == ByteKind::Code(2));
+    }
+
+    #[test]
+    fn code_ranges_cover_only_code() {
+        #[rustfmt::skip]
+        let analysis = analyse(Chip8System::XOCHIP, &[
+            0x22, 0x06, // 200: call 206
+            0x12, 0x02, // 202: jump 202
+            0xaa, 0xbb, // 204: data
+            0x00, 0xee, // 206: return
+        ]);
+        assert_eq!(analysis.code_ranges(), [0x200..0x204, 0x206..0x208]);
+        assert_eq!(analysis.label(0x206).as_deref(), Some("sub_206"));
+    }
+}
@@ -0,0 +1,1158 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs;
+use std::path::Path;
+use std::str;
+
+/// Which source line each assembled instruction or byte came from, by address.
+#[derive(Clone, Default)]
+pub struct SourceMap {
+    lines: BTreeMap<u16, usize>,
+}
+
+impl SourceMap {
+    /// The line of the instruction or byte at `addr`, counting from 1.
+    pub fn line(&self, addr: u16) -> Option<usize> {
+        self.lines.get(&addr).copied()
+    }
+
+    /// One `address line` pair per line, for other tools.
+    pub fn to_text(&self) -> String {
+        self.lines
+            .iter()
+            .map(|(addr, line)| format!("0x{:04X} {}\n", addr, line))
+            .collect()
+    }
+}
+
+/// An assembled rom and where its parts came from.
+pub struct Program {
+    pub rom: Vec<u8>,
+    pub source_map: SourceMap,
+}
+
+#[derive(Clone)]
+struct Token {
+    text: String,
+    line: usize,
+}
+
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+}
+
+/// How a label used before it's defined gets filled in.
+#[derive(Clone, Copy)]
+enum FixupKind {
+    /// The `nnn` of a 2-byte instruction.
+    Nnn,
+    /// The 16-bit address after `i := long`.
+    Long,
+}
+
+struct Fixup {
+    addr: u16,
+    name: String,
+    kind: FixupKind,
+    line: usize,
+}
+
+/// A number, or a label that isn't defined yet.
+enum Value {
+    Num(i64),
+    Forward(String),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Cmp {
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Key,
+    NotKey,
+}
+
+impl Cmp {
+    fn negate(self) -> Self {
+        match self {
+            Cmp::Eq => Cmp::Ne,
+            Cmp::Ne => Cmp::Eq,
+            Cmp::Lt => Cmp::Ge,
+            Cmp::Ge => Cmp::Lt,
+            Cmp::Gt => Cmp::Le,
+            Cmp::Le => Cmp::Gt,
+            Cmp::Key => Cmp::NotKey,
+            Cmp::NotKey => Cmp::Key,
+        }
+    }
+}
+
+enum Operand {
+    Reg(u8),
+    Num(u8),
+}
+
+struct Condition {
+    reg: u8,
+    cmp: Cmp,
+    other: Operand,
+}
+
+struct Loop {
+    start: u16,
+    /// `while` jumps out of the loop, pointed past `again` once it's reached.
+    breaks: Vec<u16>,
+}
+
+/// Words that can't be used as names.
+const KEYWORDS: [&str; 39] = [
+    ":=", "+=", "-=", "=-", "|=", "&=", "^=", ">>=", "<<=", "==", "!=", "<", ">", "<=", ">=", "i",
+    "key", "-key", "random", "delay", "buzzer", "pitch", "hex", "bighex", "long", "then", "begin",
+    "else", "end", "loop", "again", "while", "if", "jump", "jump0", "return", "clear", "sprite",
+    "call",
+];
+
+// Guards against macros that expand forever
+const MAX_EXPANSIONS: usize = 100000;
+
+struct Assembler {
+    tokens: VecDeque<Token>,
+    line: usize,
+    start: u16,
+    here: u16,
+    rom: Vec<u8>,
+    source_map: SourceMap,
+    /// The `jump main` at the start, left out if `main` comes first.
+    main_slot: bool,
+    labels: HashMap<String, u16>,
+    consts: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    expansions: usize,
+    fixups: Vec<Fixup>,
+    loops: Vec<Loop>,
+    /// Jumps past the current `begin` or `else` block, to point at its end.
+    branches: Vec<u16>,
+}
+
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut ret = VecDeque::new();
+    for (i, line) in source.lines().enumerate() {
+        let code = line.split('#').next().unwrap_or("");
+        for word in code.split_whitespace() {
+            ret.push_back(Token {
+                text: word.to_string(),
+                line: i + 1,
+            });
+        }
+    }
+    ret
+}
+
+/// Parse a decimal, `0x` hex or `0b` binary number, optionally negative.
+fn parse_num(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let lower = digits.to_ascii_lowercase();
+    let value = if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = lower.strip_prefix("0b") {
+        i64::from_str_radix(bin, 2).ok()?
+    } else if lower.starts_with(|c: char| c.is_ascii_digit()) {
+        lower.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn parse_reg(text: &str) -> Option<u8> {
+    let hex = text.strip_prefix('v').or_else(|| text.strip_prefix('V'))?;
+    if hex.len() != 1 {
+        return None;
+    }
+    u8::from_str_radix(hex, 16).ok()
+}
+
+impl Assembler {
+    fn new(source: &str, start: u16) -> Result<Self, String> {
+        // Room for `jump main`
+        let here = start
+            .checked_add(2)
+            .ok_or_else(|| format!("Start address {:#x} leaves no room for the program", start))?;
+        Ok(Self {
+            tokens: tokenize(source),
+            line: 0,
+            start,
+            here,
+            rom: vec![0, 0],
+            source_map: SourceMap::default(),
+            main_slot: true,
+            labels: HashMap::new(),
+            consts: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            expansions: 0,
+            fixups: vec![],
+            loops: vec![],
+            branches: vec![],
+        })
+    }
+
+    fn error<T>(&self, message: String) -> Result<T, String> {
+        Err(format!("Line {}: {}", self.line, message))
+    }
+
+    fn next(&mut self) -> Result<String, String> {
+        match self.tokens.pop_front() {
+            Some(token) => {
+                self.line = token.line;
+                Ok(token.text)
+            }
+            None => self.error(String::from("Unexpected end of source")),
+        }
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.tokens.front().map(|token| token.text.as_str())
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        let text = self.next()?;
+        if text != expected {
+            return self.error(format!("Expected '{}' but found '{}'", expected, text));
+        }
+        Ok(())
+    }
+
+    /// A new name for a label, constant, alias or macro.
+    fn name(&mut self) -> Result<String, String> {
+        let name = self.next()?;
+        if KEYWORDS.contains(&name.as_str())
+            || name.starts_with(':')
+            || parse_reg(&name).is_some()
+            || parse_num(&name).is_some()
+        {
+            return self.error(format!("'{}' can't be used as a name", name));
+        }
+        Ok(name)
+    }
+
+    fn reg(&mut self) -> Result<u8, String> {
+        let text = self.next()?;
+        match parse_reg(&text).or_else(|| self.aliases.get(&text).copied()) {
+            Some(reg) => Ok(reg),
+            None => self.error(format!("Expected a register but found '{}'", text)),
+        }
+    }
+
+    fn is_reg(&self, text: &str) -> bool {
+        parse_reg(text).is_some() || self.aliases.contains_key(text)
+    }
+
+    /// A number, constant or label, allowing labels that are defined later if `forward`.
+    fn value(&mut self, forward: bool) -> Result<Value, String> {
+        let text = self.next()?;
+        if let Some(num) = parse_num(&text) {
+            return Ok(Value::Num(num));
+        }
+        if let Some(value) = self.consts.get(&text) {
+            return Ok(Value::Num(*value as i64));
+        }
+        if let Some(addr) = self.labels.get(&text) {
+            return Ok(Value::Num(*addr as i64));
+        }
+        if forward && !KEYWORDS.contains(&text.as_str()) && !self.is_reg(&text) {
+            return Ok(Value::Forward(text));
+        }
+        self.error(format!("Unknown value '{}'", text))
+    }
+
+    fn num_in(&mut self, min: i64, max: i64) -> Result<i64, String> {
+        match self.value(false)? {
+            Value::Num(num) if num >= min && num <= max => Ok(num),
+            Value::Num(num) => self.error(format!("{} is out of range {}-{}", num, min, max)),
+            Value::Forward(_) => unreachable!(),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.num_in(-128, 255)? as u8)
+    }
+
+    fn nibble(&mut self) -> Result<u16, String> {
+        Ok(self.num_in(0, 15)? as u16)
+    }
+
+    fn emit_byte(&mut self, byte: u8) -> Result<(), String> {
+        let index = (self.here - self.start) as usize;
+        if index >= self.rom.len() {
+            self.rom.resize(index + 1, 0);
+        }
+        self.rom[index] = byte;
+        self.here = match self.here.checked_add(1) {
+            Some(here) => here,
+            None => return self.error(String::from("The program doesn't fit in memory")),
+        };
+        Ok(())
+    }
+
+    fn emit(&mut self, op: u16) -> Result<(), String> {
+        self.source_map.lines.insert(self.here, self.line);
+        self.emit_byte((op >> 8) as u8)?;
+        self.emit_byte(op as u8)
+    }
+
+    /// An instruction with a 12-bit address, filled in later for labels not defined yet.
+    fn emit_addr(&mut self, op: u16) -> Result<(), String> {
+        match self.value(true)? {
+            Value::Num(addr) if (0..=0xfff).contains(&addr) => self.emit(op | addr as u16),
+            Value::Num(addr) => self.error(format!("Address {:#x} is past 0xfff", addr)),
+            Value::Forward(name) => {
+                self.fixups.push(Fixup {
+                    addr: self.here,
+                    name,
+                    kind: FixupKind::Nnn,
+                    line: self.line,
+                });
+                self.emit(op)
+            }
+        }
+    }
+
+    /// A `jump` to `target`, which has to fit in its 12 bits.
+    fn jump_op(&self, target: u16) -> Result<u16, String> {
+        if target > 0xfff {
+            return self.error(format!("Can't jump to {:#x}, it's past 0xfff", target));
+        }
+        Ok(0x1000 | target)
+    }
+
+    /// Point the jump at `addr` to here.
+    fn patch_jump(&mut self, addr: u16) -> Result<(), String> {
+        let op = self.jump_op(self.here)?;
+        let index = (addr - self.start) as usize;
+        self.rom[index..index + 2].copy_from_slice(&op.to_be_bytes());
+        Ok(())
+    }
+
+    fn define_label(&mut self, name: String, addr: u16) -> Result<(), String> {
+        if self.labels.contains_key(&name) || self.consts.contains_key(&name) {
+            return self.error(format!("'{}' is already defined", name));
+        }
+        self.labels.insert(name, addr);
+        Ok(())
+    }
+
+    fn run(mut self) -> Result<Program, String> {
+        while !self.tokens.is_empty() {
+            self.statement()?;
+        }
+        if !self.loops.is_empty() {
+            return self.error(String::from("'loop' without 'again'"));
+        }
+        if !self.branches.is_empty() {
+            return self.error(String::from("'begin' without 'end'"));
+        }
+
+        if self.main_slot {
+            match self.labels.get("main") {
+                Some(&main) if main > 0xfff => {
+                    return Err(format!("'main' is at {:#x}, past 0xfff", main))
+                }
+                Some(&main) => self.rom[..2].copy_from_slice(&(0x1000 | main).to_be_bytes()),
+                None => return Err(String::from("The program doesn't define 'main'")),
+            }
+        }
+
+        for fixup in &self.fixups {
+            let addr = match self.labels.get(&fixup.name) {
+                Some(addr) => *addr,
+                None => {
+                    return Err(format!(
+                        "Line {}: Unknown name '{}'",
+                        fixup.line, fixup.name
+                    ))
+                }
+            };
+            let index = (fixup.addr - self.start) as usize;
+            match fixup.kind {
+                FixupKind::Nnn => {
+                    if addr > 0xfff {
+                        return Err(format!(
+                            "Line {}: '{}' is past 0xfff, use 'i := long'",
+                            fixup.line, fixup.name
+                        ));
+                    }
+                    self.rom[index] |= (addr >> 8) as u8;
+                    self.rom[index + 1] = addr as u8;
+                }
+                FixupKind::Long => {
+                    self.rom[index + 2] = (addr >> 8) as u8;
+                    self.rom[index + 3] = addr as u8;
+                }
+            }
+        }
+
+        Ok(Program {
+            rom: self.rom,
+            source_map: self.source_map,
+        })
+    }
+
+    fn statement(&mut self) -> Result<(), String> {
+        let text = self.next()?;
+
+        if let Some(num) = parse_num(&text) {
+            if !(-128..=255).contains(&num) {
+                return self.error(format!("{} doesn't fit in a byte", num));
+            }
+            self.source_map.lines.insert(self.here, self.line);
+            return self.emit_byte(num as u8);
+        }
+        if self.is_reg(&text) {
+            self.tokens.push_front(Token {
+                text,
+                line: self.line,
+            });
+            let reg = self.reg()?;
+            return self.reg_statement(reg);
+        }
+        if let Some(mac) = self.macros.get(&text) {
+            let args = mac.args.clone();
+            let body = mac.body.clone();
+            return self.expand(&text, args, body);
+        }
+
+        match text.as_str() {
+            ":" => {
+                let name = self.name()?;
+                // `main` first needs no jump to it, unless a label already points past the jump
+                if name == "main"
+                    && self.main_slot
+                    && self.here == self.start + 2
+                    && self.labels.is_empty()
+                {
+                    self.main_slot = false;
+                    self.here = self.start;
+                    self.rom.clear();
+                }
+                self.define_label(name, self.here)?;
+            }
+            ":next" => {
+                // The second byte of the next instruction, for code that changes itself
+                let name = self.name()?;
+                self.define_label(name, self.here + 1)?;
+            }
+            ":alias" => {
+                let name = self.name()?;
+                let reg = self.reg()?;
+                self.aliases.insert(name, reg);
+            }
+            ":const" => {
+                let name = self.name()?;
+                let value = match self.value(false)? {
+                    Value::Num(num) => num as f64,
+                    Value::Forward(_) => unreachable!(),
+                };
+                self.define_const(name, value)?;
+            }
+            ":calc" => {
+                let name = self.name()?;
+                let value = self.calc()?;
+                self.define_const(name, value)?;
+            }
+            ":byte" => {
+                let value = if self.peek() == Some("{") {
+                    self.calc()? as i64
+                } else {
+                    self.num_in(-128, 255)?
+                };
+                self.source_map.lines.insert(self.here, self.line);
+                self.emit_byte(value as u8)?;
+            }
+            ":org" => {
+                let addr = self.num_in(self.start as i64, 0xffff)?;
+                self.here = addr as u16;
+            }
+            ":macro" => {
+                let name = self.name()?;
+                let mut args = vec![];
+                while self.peek().is_some_and(|text| text != "{") {
+                    args.push(self.next()?);
+                }
+                let body = self.block()?;
+                self.macros.insert(name, Macro { args, body });
+            }
+
+            "loop" => self.loops.push(Loop {
+                start: self.here,
+                breaks: vec![],
+            }),
+            "while" => {
+                if self.loops.is_empty() {
+                    return self.error(String::from("'while' outside of a loop"));
+                }
+                let condition = self.condition()?;
+                // Jump out when the condition doesn't hold
+                self.skip_unless(&condition, true)?;
+                let addr = self.here;
+                self.emit(0x1000)?;
+                self.loops.last_mut().unwrap().breaks.push(addr);
+            }
+            "again" => {
+                let lp = match self.loops.pop() {
+                    Some(lp) => lp,
+                    None => return self.error(String::from("'again' without 'loop'")),
+                };
+                let op = self.jump_op(lp.start)?;
+                self.emit(op)?;
+                for addr in lp.breaks {
+                    self.patch_jump(addr)?;
+                }
+            }
+            "if" => {
+                let condition = self.condition()?;
+                match self.next()?.as_str() {
+                    "then" => self.skip_unless(&condition, false)?,
+                    "begin" => {
+                        // Jump past the block when the condition doesn't hold
+                        self.skip_unless(&condition, true)?;
+                        self.branches.push(self.here);
+                        self.emit(0x1000)?;
+                    }
+                    other => {
+                        return self
+                            .error(format!("Expected 'then' or 'begin' but found '{}'", other))
+                    }
+                }
+            }
+            "else" => {
+                let addr = match self.branches.pop() {
+                    Some(addr) => addr,
+                    None => return self.error(String::from("'else' without 'begin'")),
+                };
+                self.branches.push(self.here);
+                self.emit(0x1000)?;
+                self.patch_jump(addr)?;
+            }
+            "end" => match self.branches.pop() {
+                Some(addr) => self.patch_jump(addr)?,
+                None => return self.error(String::from("'end' without 'begin'")),
+            },
+
+            "return" | ";" => self.emit(0x00ee)?,
+            "clear" => self.emit(0x00e0)?,
+            "exit" => self.emit(0x00fd)?,
+            "lores" => self.emit(0x00fe)?,
+            "hires" => self.emit(0x00ff)?,
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.emit(0x00c0 | n)?;
+            }
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.emit(0x00d0 | n)?;
+            }
+            "scroll-right" => self.emit(0x00fb)?,
+            "scroll-left" => self.emit(0x00fc)?,
+            "audio" => self.emit(0xf002)?,
+            "plane" => {
+                let n = self.nibble()?;
+                self.emit(0xf001 | n << 8)?;
+            }
+            "jump" => self.emit_addr(0x1000)?,
+            "call" => self.emit_addr(0x2000)?,
+            "jump0" => self.emit_addr(0xb000)?,
+            "native" => self.emit_addr(0x0000)?,
+            "sprite" => {
+                let x = self.reg()? as u16;
+                let y = self.reg()? as u16;
+                let n = self.nibble()?;
+                self.emit(0xd000 | x << 8 | y << 4 | n)?;
+            }
+            "bcd" => {
+                let x = self.reg()? as u16;
+                self.emit(0xf033 | x << 8)?;
+            }
+            "save" | "load" => {
+                let x = self.reg()? as u16;
+                if self.peek() == Some("-") {
+                    self.next()?;
+                    let y = self.reg()? as u16;
+                    let op = if text == "save" { 0x5002 } else { 0x5003 };
+                    self.emit(op | x << 8 | y << 4)?;
+                } else {
+                    let op = if text == "save" { 0xf055 } else { 0xf065 };
+                    self.emit(op | x << 8)?;
+                }
+            }
+            "saveflags" => {
+                let x = self.reg()? as u16;
+                self.emit(0xf075 | x << 8)?;
+            }
+            "loadflags" => {
+                let x = self.reg()? as u16;
+                self.emit(0xf085 | x << 8)?;
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.reg()? as u16;
+                let op = match text.as_str() {
+                    "delay" => 0xf015,
+                    "buzzer" => 0xf018,
+                    _ => 0xf03a,
+                };
+                self.emit(op | x << 8)?;
+            }
+            "i" => self.i_statement()?,
+
+            _ if text.starts_with(':') => {
+                return self.error(format!("Unknown directive '{}'", text));
+            }
+            _ if KEYWORDS.contains(&text.as_str()) => {
+                return self.error(format!("Unexpected '{}'", text));
+            }
+            // Anything else is a subroutine to call
+            _ => {
+                self.tokens.push_front(Token {
+                    text,
+                    line: self.line,
+                });
+                self.emit_addr(0x2000)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn define_const(&mut self, name: String, value: f64) -> Result<(), String> {
+        if self.labels.contains_key(&name) {
+            return self.error(format!("'{}' is already a label", name));
+        }
+        self.consts.insert(name, value);
+        Ok(())
+    }
+
+    /// The tokens between `{` and its matching `}`.
+    fn block(&mut self) -> Result<Vec<Token>, String> {
+        self.expect("{")?;
+        let mut ret = vec![];
+        let mut depth = 1;
+        loop {
+            let text = self.next()?;
+            match text.as_str() {
+                "{" => depth += 1,
+                "}" => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(ret);
+                    }
+                }
+                _ => (),
+            }
+            ret.push(Token {
+                text,
+                line: self.line,
+            });
+        }
+    }
+
+    /// Replace a macro's use with its body, taking as many tokens as it has arguments.
+    fn expand(&mut self, name: &str, args: Vec<String>, body: Vec<Token>) -> Result<(), String> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return self.error(format!("Macro '{}' expands too many times", name));
+        }
+        let mut values = HashMap::new();
+        for arg in args {
+            let value = self.next()?;
+            values.insert(arg, value);
+        }
+        for token in body.into_iter().rev() {
+            let text = values.get(&token.text).cloned().unwrap_or(token.text);
+            self.tokens.push_front(Token {
+                text,
+                line: token.line,
+            });
+        }
+        Ok(())
+    }
+
+    fn reg_statement(&mut self, x: u8) -> Result<(), String> {
+        let x = x as u16;
+        let op = self.next()?;
+        let is_reg = self.peek().is_some_and(|text| self.is_reg(text));
+
+        match op.as_str() {
+            ":=" => match self.peek() {
+                Some("random") => {
+                    self.next()?;
+                    let nn = self.byte()? as u16;
+                    self.emit(0xc000 | x << 8 | nn)
+                }
+                Some("delay") => {
+                    self.next()?;
+                    self.emit(0xf007 | x << 8)
+                }
+                Some("key") => {
+                    self.next()?;
+                    self.emit(0xf00a | x << 8)
+                }
+                _ if is_reg => {
+                    let y = self.reg()? as u16;
+                    self.emit(0x8000 | x << 8 | y << 4)
+                }
+                _ => {
+                    let nn = self.byte()? as u16;
+                    self.emit(0x6000 | x << 8 | nn)
+                }
+            },
+            "+=" if !is_reg => {
+                let nn = self.byte()? as u16;
+                self.emit(0x7000 | x << 8 | nn)
+            }
+            "-=" if !is_reg => {
+                let nn = self.byte()?.wrapping_neg() as u16;
+                self.emit(0x7000 | x << 8 | nn)
+            }
+            "|=" | "&=" | "^=" | "+=" | "-=" | ">>=" | "=-" | "<<=" => {
+                let y = self.reg()? as u16;
+                let n = match op.as_str() {
+                    "|=" => 0x1,
+                    "&=" => 0x2,
+                    "^=" => 0x3,
+                    "+=" => 0x4,
+                    "-=" => 0x5,
+                    ">>=" => 0x6,
+                    "=-" => 0x7,
+                    _ => 0xe,
+                };
+                self.emit(0x8000 | x << 8 | y << 4 | n)
+            }
+            _ => self.error(format!("Unknown register operation '{}'", op)),
+        }
+    }
+
+    fn i_statement(&mut self) -> Result<(), String> {
+        let op = self.next()?;
+        match op.as_str() {
+            ":=" => match self.peek() {
+                Some("hex") => {
+                    self.next()?;
+                    let x = self.reg()? as u16;
+                    self.emit(0xf029 | x << 8)
+                }
+                Some("bighex") => {
+                    self.next()?;
+                    let x = self.reg()? as u16;
+                    self.emit(0xf030 | x << 8)
+                }
+                Some("long") => {
+                    self.next()?;
+                    let addr = self.here;
+                    let value = self.value(true)?;
+                    self.emit(0xf000)?;
+                    match value {
+                        Value::Num(num) if (0..=0xffff).contains(&num) => self.emit(num as u16),
+                        Value::Num(num) => self.error(format!("Address {:#x} is past 0xffff", num)),
+                        Value::Forward(name) => {
+                            self.fixups.push(Fixup {
+                                addr,
+                                name,
+                                kind: FixupKind::Long,
+                                line: self.line,
+                            });
+                            self.emit(0)
+                        }
+                    }
+                }
+                _ => self.emit_addr(0xa000),
+            },
+            "+=" => {
+                let x = self.reg()? as u16;
+                self.emit(0xf01e | x << 8)
+            }
+            _ => self.error(format!("Unknown operation 'i {}'", op)),
+        }
+    }
+
+    fn condition(&mut self) -> Result<Condition, String> {
+        let reg = self.reg()?;
+        let text = self.next()?;
+        let cmp = match text.as_str() {
+            "key" => Cmp::Key,
+            "-key" => Cmp::NotKey,
+            "==" => Cmp::Eq,
+            "!=" => Cmp::Ne,
+            "<" => Cmp::Lt,
+            ">" => Cmp::Gt,
+            "<=" => Cmp::Le,
+            ">=" => Cmp::Ge,
+            _ => return self.error(format!("Unknown comparison '{}'", text)),
+        };
+        let other = match cmp {
+            Cmp::Key | Cmp::NotKey => Operand::Num(0),
+            _ if self.peek().is_some_and(|text| self.is_reg(text)) => Operand::Reg(self.reg()?),
+            _ => Operand::Num(self.byte()?),
+        };
+        Ok(Condition { reg, cmp, other })
+    }
+
+    /// Emit what makes the next instruction run only if the condition holds, or only if
+    /// it doesn't when `negate`. Comparing order goes through vf.
+    fn skip_unless(&mut self, condition: &Condition, negate: bool) -> Result<(), String> {
+        let x = condition.reg as u16;
+        let cmp = if negate {
+            condition.cmp.negate()
+        } else {
+            condition.cmp
+        };
+        match (cmp, &condition.other) {
+            (Cmp::Eq, Operand::Num(nn)) => self.emit(0x4000 | x << 8 | *nn as u16),
+            (Cmp::Ne, Operand::Num(nn)) => self.emit(0x3000 | x << 8 | *nn as u16),
+            (Cmp::Eq, Operand::Reg(y)) => self.emit(0x9000 | x << 8 | (*y as u16) << 4),
+            (Cmp::Ne, Operand::Reg(y)) => self.emit(0x5000 | x << 8 | (*y as u16) << 4),
+            (Cmp::Key, _) => self.emit(0xe0a1 | x << 8),
+            (Cmp::NotKey, _) => self.emit(0xe09e | x << 8),
+            _ => {
+                // vf := other, then subtract so vf ends up as the no-borrow flag
+                match condition.other {
+                    Operand::Num(nn) => self.emit(0x6f00 | nn as u16)?,
+                    Operand::Reg(y) => self.emit(0x8f00 | (y as u16) << 4)?,
+                }
+                let (sub, flag) = match cmp {
+                    // vf = other - x, no borrow when other >= x
+                    Cmp::Gt => (0x8f05, 0),
+                    Cmp::Le => (0x8f05, 1),
+                    // vf = x - other, no borrow when x >= other
+                    Cmp::Ge => (0x8f07, 1),
+                    _ => (0x8f07, 0),
+                };
+                self.emit(sub | x << 4)?;
+                self.emit(0x4f00 | flag)
+            }
+        }
+    }
+
+    /// Evaluate `{ ... }` the way Octo does, right to left with no precedence.
+    fn calc(&mut self) -> Result<f64, String> {
+        let tokens = self.block()?;
+        let mut pos = 0;
+        let value = self.calc_expr(&tokens, &mut pos)?;
+        if pos != tokens.len() {
+            return self.error(format!("Unexpected '{}' in expression", tokens[pos].text));
+        }
+        Ok(value)
+    }
+
+    fn calc_expr(&self, tokens: &[Token], pos: &mut usize) -> Result<f64, String> {
+        let lhs = self.calc_term(tokens, pos)?;
+        let op = match tokens.get(*pos) {
+            Some(token) if token.text != ")" => token.text.clone(),
+            _ => return Ok(lhs),
+        };
+        *pos += 1;
+        let rhs = self.calc_expr(tokens, pos)?;
+        let (a, b) = (lhs as i64, rhs as i64);
+        Ok(match op.as_str() {
+            "+" => lhs + rhs,
+            "-" => lhs - rhs,
+            "*" => lhs * rhs,
+            "/" => lhs / rhs,
+            "%" => lhs % rhs,
+            "&" => (a & b) as f64,
+            "|" => (a | b) as f64,
+            "^" => (a ^ b) as f64,
+            "<<" => (a << (b & 63)) as f64,
+            ">>" => (a >> (b & 63)) as f64,
+            "min" => lhs.min(rhs),
+            "max" => lhs.max(rhs),
+            "pow" => lhs.powf(rhs),
+            "<" => (lhs < rhs) as i64 as f64,
+            ">" => (lhs > rhs) as i64 as f64,
+            "<=" => (lhs <= rhs) as i64 as f64,
+            ">=" => (lhs >= rhs) as i64 as f64,
+            "==" => (lhs == rhs) as i64 as f64,
+            "!=" => (lhs != rhs) as i64 as f64,
+            _ => return self.error(format!("Unknown operator '{}'", op)),
+        })
+    }
+
+    fn calc_term(&self, tokens: &[Token], pos: &mut usize) -> Result<f64, String> {
+        let text = match tokens.get(*pos) {
+            Some(token) => token.text.as_str(),
+            None => return self.error(String::from("Unexpected end of expression")),
+        };
+        *pos += 1;
+        match text {
+            "(" => {
+                let value = self.calc_expr(tokens, pos)?;
+                match tokens.get(*pos) {
+                    Some(token) if token.text == ")" => *pos += 1,
+                    _ => return self.error(String::from("Expected ')'")),
+                }
+                Ok(value)
+            }
+            "-" => Ok(-self.calc_term(tokens, pos)?),
+            "~" => Ok(!(self.calc_term(tokens, pos)? as i64) as f64),
+            "!" => Ok((self.calc_term(tokens, pos)? == 0.0) as i64 as f64),
+            "abs" => Ok(self.calc_term(tokens, pos)?.abs()),
+            "sqrt" => Ok(self.calc_term(tokens, pos)?.sqrt()),
+            "floor" => Ok(self.calc_term(tokens, pos)?.floor()),
+            "ceil" => Ok(self.calc_term(tokens, pos)?.ceil()),
+            "@" => {
+                // A byte already assembled
+                let addr = self.calc_term(tokens, pos)? as i64;
+                let index = addr - self.start as i64;
+                Ok(usize::try_from(index)
+                    .ok()
+                    .and_then(|index| self.rom.get(index))
+                    .map_or(0.0, |byte| *byte as f64))
+            }
+            "HERE" => Ok(self.here as f64),
+            _ => {
+                if let Some(num) = parse_num(text) {
+                    Ok(num as f64)
+                } else if let Some(value) = self.consts.get(text) {
+                    Ok(*value)
+                } else if let Some(addr) = self.labels.get(text) {
+                    Ok(*addr as f64)
+                } else {
+                    self.error(format!("Unknown name '{}' in expression", text))
+                }
+            }
+        }
+    }
+}
+
+/// Assemble Octo source into a rom to load at `start`.
+pub fn assemble(source: &str, start: u16) -> Result<Program, String> {
+    Assembler::new(source, start)?.run()
+}
+
+/// Read a rom file as it is on disk, which for Octo source is the source. Per-ROM
+/// settings go by its hash, as they decide where source gets assembled for.
+pub fn read_rom(path: &Path) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|err| format!("Couldn't read {}: {}", path.to_string_lossy(), err))
+}
+
+/// The rom in a file read by `read_rom`, assembled for `start` if it's Octo source.
+pub fn build(path: &Path, contents: &[u8], start: u16) -> Result<Program, String> {
+    let name = path.to_string_lossy();
+    if path.extension().is_some_and(|ext| ext == "8o") {
+        let source =
+            str::from_utf8(contents).map_err(|err| format!("Couldn't read {}: {}", name, err))?;
+        assemble(source, start).map_err(|err| format!("{}: {}", name, err))
+    } else {
+        Ok(Program {
+            rom: contents.to_vec(),
+            source_map: SourceMap::default(),
+        })
+    }
+}
+
+/// Assemble `source` to the rom file `out`, with its source map next to it as `.map`.
+pub fn assemble_to(source: &Path, contents: &[u8], out: &Path, start: u16) -> Result<(), String> {
+    let program = build(source, contents, start)?;
+    let map_path = out.with_extension("map");
+    fs::write(out, &program.rom)
+        .map_err(|err| format!("Couldn't write {}: {}", out.to_string_lossy(), err))?;
+    fs::write(&map_path, program.source_map.to_text())
+        .map_err(|err| format!("Couldn't write {}: {}", map_path.to_string_lossy(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(source: &str) -> Vec<u8> {
+        match assemble(source, 0x200) {
+            Ok(program) => program.rom,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    fn error(source: &str, start: u16) -> String {
+        match assemble(source, start) {
+            Ok(_) => panic!("'{}' assembled", source),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn main_slot() {
+        // Left out when main comes first
+        assert_eq!(rom(": main clear"), [0x00, 0xe0]);
+        assert_eq!(
+            rom(": sub ; : main sub"),
+            [0x12, 0x04, 0x00, 0xee, 0x22, 0x02]
+        );
+        // A label before main is at the slot's end, so main can't move back over it
+        assert_eq!(rom(": foo : main jump foo"), [0x12, 0x02, 0x12, 0x02]);
+        assert_eq!(error("clear", 0x200), "The program doesn't define 'main'");
+    }
+
+    #[test]
+    fn forward_labels() {
+        let source = "
+            : main
+                jump done
+                i := ball
+                call sub
+            : done ;
+            : sub ;
+            : ball 0x3c
+        ";
+        assert_eq!(
+            rom(source),
+            [0x12, 0x06, 0xa2, 0x0a, 0x22, 0x08, 0x00, 0xee, 0x00, 0xee, 0x3c]
+        );
+
+        let rom = rom(": main i := long data :org 0x1000 : data 1");
+        assert_eq!(rom.len(), 0xe01);
+        assert_eq!(rom[..4], [0xf0, 0x00, 0x10, 0x00]);
+        assert_eq!(rom[0xe00], 1);
+
+        assert_eq!(
+            error(": main jump nowhere", 0x200),
+            "Line 1: Unknown name 'nowhere'"
+        );
+    }
+
+    #[test]
+    fn directives() {
+        let source = "
+            :alias x v3
+            :const N 5
+            :calc M { N * 2 + 1 }
+            :macro twice op { op op }
+            : main
+                x := N
+                x += M
+                twice clear
+                :next target x := 0
+                i := target
+                :byte { 10 - 2 - 1 }
+                jump there
+            :org 0x212
+            : there ;
+        ";
+        #[rustfmt::skip]
+        assert_eq!(rom(source), [
+            0x63, 0x05, // x := N
+            0x73, 0x0f, // M is N * (2 + 1), as Octo goes right to left
+            0x00, 0xe0, 0x00, 0xe0,
+            0x63, 0x00,
+            0xa2, 0x09, // the byte after 0x63
+            0x09,       // 10 - (2 - 1)
+            0x12, 0x12,
+            0x00, 0x00, 0x00,
+            0x00, 0xee, // at 0x212
+        ]);
+    }
+
+    #[test]
+    fn loops() {
+        let source = "
+            : main
+                loop
+                    v0 += 1
+                    while v0 != 5
+                again
+        ";
+        #[rustfmt::skip]
+        assert_eq!(rom(source), [
+            0x70, 0x01,
+            0x40, 0x05, // skip the jump out while v0 != 5
+            0x12, 0x08,
+            0x12, 0x00,
+        ]);
+    }
+
+    #[test]
+    fn branches() {
+        let source = "
+            : main
+                if v1 == v2 begin
+                    clear
+                else
+                    return
+                end
+                if v0 key then clear
+                if v1 > 3 then clear
+        ";
+        #[rustfmt::skip]
+        assert_eq!(rom(source), [
+            0x51, 0x20, 0x12, 0x08, // past the block unless v1 == v2
+            0x00, 0xe0, 0x12, 0x0a, // past the else block
+            0x00, 0xee,
+            0xe0, 0xa1, 0x00, 0xe0,
+            // vf := 3 - v1, which borrows when v1 > 3
+            0x6f, 0x03, 0x8f, 0x15, 0x4f, 0x00, 0x00, 0xe0,
+        ]);
+        assert_eq!(error(": main end", 0x200), "Line 1: 'end' without 'begin'");
+        assert_eq!(
+            error(": main loop", 0x200),
+            "Line 1: 'loop' without 'again'"
+        );
+    }
+
+    #[test]
+    fn source_map() {
+        let program = assemble(": main\n  clear\n\n  v0 := 1\n  :byte 2\n", 0x200).unwrap();
+        assert_eq!(program.source_map.line(0x200), Some(2));
+        assert_eq!(program.source_map.line(0x202), Some(4));
+        assert_eq!(program.source_map.line(0x204), Some(5));
+        assert_eq!(program.source_map.line(0x205), None);
+        assert_eq!(
+            program.source_map.to_text(),
+            "0x0200 2\n0x0202 4\n0x0204 5\n"
+        );
+    }
+
+    #[test]
+    fn addresses_out_of_range() {
+        assert_eq!(
+            error(": main", 0xfffe),
+            "Start address 0xfffe leaves no room for the program"
+        );
+        assert!(assemble(": main", 0xffff).is_err());
+        assert_eq!(
+            error(": main jump 0x1000", 0x200),
+            "Line 1: Address 0x1000 is past 0xfff"
+        );
+        assert_eq!(
+            error(": main jump far :org 0x1000 : far", 0x200),
+            "Line 1: 'far' is past 0xfff, use 'i := long'"
+        );
+        assert_eq!(
+            error(":org 0x1000 : main loop again", 0x200),
+            "Line 1: Can't jump to 0x1000, it's past 0xfff"
+        );
+        assert_eq!(
+            error(": foo :org 0x1000 : main ;", 0x200),
+            "'main' is at 0x1000, past 0xfff"
+        );
+        assert_eq!(
+            error(":org 0xffe : main if v0 == 1 begin ; end", 0x200),
+            "Line 1: Can't jump to 0x1004, it's past 0xfff"
+        );
+    }
+
+    #[test]
+    fn build_assembles_only_source() {
+        let source = b": main loop again";
+        let program = build(Path::new("a.8o"), source, 0x600).unwrap();
+        assert_eq!(program.rom, [0x16, 0x00]);
+        assert_eq!(program.source_map.line(0x600), Some(1));
+
+        let program = build(Path::new("a.ch8"), source, 0x600).unwrap();
+        assert_eq!(program.rom, source);
+        assert_eq!(program.source_map.line(0x600), None);
+    }
+}
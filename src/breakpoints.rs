@@ -1,14 +1,66 @@
-use egui::Ui;
+use crate::chip8::Chip8;
+use crate::expr::Expr;
 
+use egui::{DragValue, Ui};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Breakpoint {
     pub addr_start: u16,
     pub addr_end: u16,
+    /// Only break when this evaluates to non-zero, eg `v3 == 0x10 && [I] != 0`.
+    pub condition: Option<String>,
+    pub enabled: bool,
+    /// Times the breakpoint was reached with its condition true.
+    pub hit_count: u32,
+    /// Hits to let through before breaking.
+    pub ignore_count: u32,
+    /// `condition`, parsed on first use.
+    #[serde(skip)]
+    expr: Option<Expr>,
+}
+
+// Leaves out `expr`, which only caches `condition`
+impl PartialEq for Breakpoint {
+    fn eq(&self, other: &Self) -> bool {
+        self.addr_start == other.addr_start
+            && self.addr_end == other.addr_end
+            && self.condition == other.condition
+            && self.enabled == other.enabled
+            && self.hit_count == other.hit_count
+            && self.ignore_count == other.ignore_count
+    }
+}
+
+impl Breakpoint {
+    /// Whether execution should stop at `chip8.pc`, counting the hit if so.
+    fn check(&mut self, chip8: &Chip8) -> bool {
+        if !self.enabled || chip8.pc < self.addr_start || chip8.pc > self.addr_end {
+            return false;
+        }
+        if let Some(condition) = &self.condition {
+            if self.expr.is_none() {
+                // Conditions are checked when added, so this only fails for edited settings
+                match Expr::parse(condition) {
+                    Ok(expr) => self.expr = Some(expr),
+                    Err(_) => return false,
+                }
+            }
+            if !self.expr.as_ref().unwrap().test(chip8) {
+                return false;
+            }
+        }
+        self.hit_count += 1;
+        self.hit_count > self.ignore_count
+    }
 }
 
 pub struct Breakpoints {
     addr_start: String,
     addr_end: String,
-    breakpoints: Vec<Breakpoint>,
+    condition: String,
+    condition_error: Option<String>,
+    pub breakpoints: Vec<Breakpoint>,
 }
 
 impl Breakpoints {
@@ -16,6 +68,8 @@ impl Breakpoints {
         Self {
             addr_start: String::from(""),
             addr_end: String::from(""),
+            condition: String::from(""),
+            condition_error: None,
             breakpoints: vec![],
         }
     }
@@ -40,27 +94,64 @@ impl Breakpoints {
             }
         });
 
+        ui.horizontal(|ui| {
+            let condition_label = ui.label("Condition:");
+            ui.text_edit_singleline(&mut self.condition)
+                .labelled_by(condition_label.id)
+                .on_hover_text("eg v3 == 0x10 && [I] != 0, using v0-vF, I, PC, DT, ST and SP");
+        });
+
         if ui.button("Add breakpoint").clicked() {
             if self.addr_start.len() > 0 && self.addr_end.len() > 0 {
                 let addr_start = u16::from_str_radix(&self.addr_start, 16).ok().unwrap();
                 let addr_end = u16::from_str_radix(&self.addr_end, 16).ok().unwrap();
-                self.breakpoints.push(Breakpoint {
-                    addr_start: addr_start,
-                    addr_end: addr_end,
-                });
+                let condition = self.condition.trim();
+                let parsed = if condition.is_empty() {
+                    Ok(None)
+                } else {
+                    Expr::parse(condition).map(Some)
+                };
+                match parsed {
+                    Ok(expr) => {
+                        self.condition_error = None;
+                        self.breakpoints.push(Breakpoint {
+                            addr_start,
+                            addr_end,
+                            condition: expr.as_ref().map(|_| condition.to_string()),
+                            enabled: true,
+                            hit_count: 0,
+                            ignore_count: 0,
+                            expr,
+                        });
+                    }
+                    Err(err) => self.condition_error = Some(err),
+                }
             }
         }
+        if let Some(err) = &self.condition_error {
+            ui.label(err.as_str());
+        }
 
         if self.breakpoints.len() > 0 {
             ui.separator();
             let mut removed = None;
             for i in 0..self.breakpoints.len() {
-                let breakpoint = &self.breakpoints[i];
+                let breakpoint = &mut self.breakpoints[i];
                 ui.horizontal(|ui| {
+                    ui.checkbox(&mut breakpoint.enabled, "");
                     ui.label(format!(
                         "{}: {:03x}-{:03x}",
                         i, breakpoint.addr_start, breakpoint.addr_end
                     ));
+                    if let Some(condition) = &breakpoint.condition {
+                        ui.label(format!("if {}", condition));
+                    }
+                    ui.label(format!("Hits: {}", breakpoint.hit_count));
+                    ui.label("Ignore:");
+                    ui.add(DragValue::new(&mut breakpoint.ignore_count).speed(0.1));
+                    if ui.button("Reset").clicked() {
+                        breakpoint.hit_count = 0;
+                    }
                     if ui.button("Remove").clicked() {
                         removed = Some(i);
                     }
@@ -75,12 +166,110 @@ impl Breakpoints {
         }
     }
 
-    pub fn check(&mut self, pc: u16) -> bool {
-        for breakpoint in &self.breakpoints {
-            if pc >= breakpoint.addr_start && pc <= breakpoint.addr_end {
-                return true;
+    pub fn clear(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    /// Whether an enabled breakpoint covers the address.
+    pub fn covers(&self, addr: u16) -> bool {
+        self.breakpoints.iter().any(|breakpoint| {
+            breakpoint.enabled && addr >= breakpoint.addr_start && addr <= breakpoint.addr_end
+        })
+    }
+
+    /// Remove the breakpoints on just this address, or add one if there are none.
+    pub fn toggle(&mut self, addr: u16) {
+        let count = self.breakpoints.len();
+        self.breakpoints
+            .retain(|breakpoint| breakpoint.addr_start != addr || breakpoint.addr_end != addr);
+        if self.breakpoints.len() == count {
+            self.breakpoints.push(Breakpoint {
+                addr_start: addr,
+                addr_end: addr,
+                condition: None,
+                enabled: true,
+                hit_count: 0,
+                ignore_count: 0,
+                expr: None,
+            });
+        }
+    }
+
+    /// Address ranges of the enabled breakpoints, for the JIT to stop at.
+    pub fn ranges(&self) -> Vec<(u16, u16)> {
+        self.breakpoints
+            .iter()
+            .filter(|breakpoint| breakpoint.enabled)
+            .map(|breakpoint| (breakpoint.addr_start, breakpoint.addr_end))
+            .collect()
+    }
+
+    pub fn check(&mut self, chip8: &Chip8) -> bool {
+        let mut hit = false;
+        // Check them all, so each one's hit count is kept up to date
+        for breakpoint in &mut self.breakpoints {
+            if breakpoint.check(chip8) {
+                hit = true;
             }
         }
-        false
+        hit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breakpoint(condition: Option<&str>, ignore_count: u32) -> Breakpoint {
+        Breakpoint {
+            addr_start: 0x200,
+            addr_end: 0x204,
+            condition: condition.map(String::from),
+            enabled: true,
+            hit_count: 0,
+            ignore_count,
+            expr: None,
+        }
+    }
+
+    #[test]
+    fn ignore_count_lets_hits_through() {
+        let chip8 = Chip8::new();
+        let mut breakpoint = breakpoint(None, 2);
+        assert!(!breakpoint.check(&chip8));
+        assert!(!breakpoint.check(&chip8));
+        assert!(breakpoint.check(&chip8));
+        assert!(breakpoint.check(&chip8));
+        assert_eq!(breakpoint.hit_count, 4);
+    }
+
+    #[test]
+    fn only_hits_in_range_with_the_condition_true_count() {
+        let mut chip8 = Chip8::new();
+        let mut breakpoint = breakpoint(Some("v3 == 0x10"), 0);
+        assert!(!breakpoint.check(&chip8));
+        chip8.regs[3] = 0x10;
+        assert!(breakpoint.check(&chip8));
+        chip8.pc = 0x206;
+        assert!(!breakpoint.check(&chip8));
+        assert_eq!(breakpoint.hit_count, 1);
+    }
+
+    #[test]
+    fn disabled_breakpoints_dont_count() {
+        let chip8 = Chip8::new();
+        let mut breakpoint = breakpoint(None, 0);
+        breakpoint.enabled = false;
+        assert!(!breakpoint.check(&chip8));
+        assert_eq!(breakpoint.hit_count, 0);
+    }
+
+    #[test]
+    fn equality_ignores_the_parsed_condition() {
+        let chip8 = Chip8::new();
+        let mut parsed = breakpoint(Some("v0 == 1"), 0);
+        parsed.check(&chip8);
+        assert!(parsed.expr.is_some());
+        assert!(parsed == breakpoint(Some("v0 == 1"), 0));
     }
 }
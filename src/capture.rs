@@ -0,0 +1,303 @@
+use crate::constants::{HEIGHT, WIDTH};
+use crate::palette::Palette;
+use crate::renderer::{Screen, ScreenRenderer};
+
+use log::warn;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Most viewers treat smaller GIF delays as 10 centiseconds
+const MIN_GIF_DELAY: u32 = 2;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum RecordFormat {
+    Gif,
+    /// Raw RGBA frames back to back, eg for `ffmpeg -f rawvideo -pix_fmt rgba`.
+    Raw,
+}
+
+impl RecordFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            RecordFormat::Gif => "gif",
+            RecordFormat::Raw => "rgba",
+        }
+    }
+
+    /// Raw for `.rgba` files, otherwise GIF.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension() {
+            Some(ext) if ext == "rgba" => RecordFormat::Raw,
+            _ => RecordFormat::Gif,
+        }
+    }
+}
+
+/// A file name like `screenshot-1700000000.png` in the current dir.
+pub fn timestamped_path(prefix: &str, extension: &str) -> PathBuf {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    PathBuf::from(format!("{}-{}.{}", prefix, secs, extension))
+}
+
+/// Nearest-neighbour resize of an image with `bytes_per_pixel` bytes per pixel.
+fn resize(
+    src: &[u8],
+    bytes_per_pixel: usize,
+    src_size: (usize, usize),
+    dst_size: (usize, usize),
+) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(dst_size.0 * dst_size.1 * bytes_per_pixel);
+    for y in 0..dst_size.1 {
+        let src_y = y * src_size.1 / dst_size.1;
+        for x in 0..dst_size.0 {
+            let src_x = x * src_size.0 / dst_size.0;
+            let offs = (src_y * src_size.0 + src_x) * bytes_per_pixel;
+            ret.extend_from_slice(&src[offs..offs + bytes_per_pixel]);
+        }
+    }
+    ret
+}
+
+/// Write the screen as it's shown by `renderer` to a PNG, `scale` times larger.
+pub fn save_screenshot(
+    path: &Path,
+    screen: &Screen,
+    palette: &Palette,
+    renderer: &ScreenRenderer,
+    scale: usize,
+) -> Result<(), String> {
+    let (width, height) = renderer.size(screen);
+    let mut frame = vec![0; width * height * 4];
+    renderer.render(screen, palette, &mut frame);
+    let frame = resize(&frame, 4, (width, height), (width * scale, height * scale));
+
+    let file = File::create(path).map_err(|err| err.to_string())?;
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(file),
+        (width * scale) as u32,
+        (height * scale) as u32,
+    );
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(|err| err.to_string())?;
+    writer
+        .write_image_data(&frame)
+        .map_err(|err| err.to_string())?;
+    writer.finish().map_err(|err| err.to_string())
+}
+
+enum Output {
+    Gif {
+        encoder: gif::Encoder<BufWriter<File>>,
+        /// The last frame, written once it changes.
+        pending: Option<Vec<u8>>,
+        /// Emulated frames captured so far.
+        frames: u32,
+        centisecs: u32,
+    },
+    Raw(BufWriter<File>),
+}
+
+/// Records every emulated frame at a fixed size, so that it doesn't change when
+/// a ROM switches between lores and hires.
+pub struct Recorder {
+    output: Option<Output>,
+    path: PathBuf,
+    /// Frames are resized to this before scaling.
+    size: (usize, usize),
+    scale: usize,
+    renderer: ScreenRenderer,
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Self {
+            output: None,
+            path: PathBuf::new(),
+            size: (WIDTH, HEIGHT),
+            scale: 1,
+            renderer: ScreenRenderer::new(),
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.output.is_some()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Start recording frames of `size` pixels to `path`, `scale` times larger,
+    /// finishing any recording in progress.
+    pub fn start(
+        &mut self,
+        path: &Path,
+        format: RecordFormat,
+        size: (usize, usize),
+        scale: usize,
+    ) -> Result<(), String> {
+        self.stop();
+
+        let file = BufWriter::new(File::create(path).map_err(|err| err.to_string())?);
+        let output = match format {
+            RecordFormat::Gif => {
+                let width = (size.0 * scale) as u16;
+                let height = (size.1 * scale) as u16;
+                let mut encoder =
+                    gif::Encoder::new(file, width, height, &[]).map_err(|err| err.to_string())?;
+                encoder
+                    .set_repeat(gif::Repeat::Infinite)
+                    .map_err(|err| err.to_string())?;
+                Output::Gif {
+                    encoder,
+                    pending: None,
+                    frames: 0,
+                    centisecs: 0,
+                }
+            }
+            RecordFormat::Raw => Output::Raw(file),
+        };
+
+        self.output = Some(output);
+        self.path = path.to_path_buf();
+        self.size = size;
+        self.scale = scale;
+        Ok(())
+    }
+
+    /// Add a frame to the recording, if there is one.
+    pub fn capture(&mut self, screen: &Screen, palette: &Palette) {
+        let output = match &mut self.output {
+            Some(output) => output,
+            None => return,
+        };
+
+        let (width, height) = self.renderer.size(screen);
+        let mut frame = vec![0; width * height * 4];
+        self.renderer.render(screen, palette, &mut frame);
+        let frame = resize(&frame, 4, (width, height), self.size);
+
+        let result = match output {
+            Output::Gif {
+                encoder,
+                pending,
+                frames,
+                centisecs,
+            } => {
+                *frames += 1;
+
+                // Show unchanged frames for longer rather than repeating them
+                match pending {
+                    Some(pixels) if *pixels == frame => Ok(()),
+                    _ => match pending.replace(frame) {
+                        Some(pixels) => {
+                            let end = *frames - 1;
+                            write_gif_frame(encoder, &pixels, self.size, self.scale, end, centisecs)
+                        }
+                        None => Ok(()),
+                    },
+                }
+            }
+            Output::Raw(file) => {
+                let scaled_size = (self.size.0 * self.scale, self.size.1 * self.scale);
+                let frame = resize(&frame, 4, self.size, scaled_size);
+                file.write_all(&frame).map_err(|err| err.to_string())
+            }
+        };
+
+        if let Err(err) = result {
+            warn!("Stopped recording {}: {}", self.path.to_string_lossy(), err);
+            self.output = None;
+        }
+    }
+
+    /// Finish the file being recorded, if any.
+    pub fn stop(&mut self) {
+        let result = match self.output.take() {
+            Some(Output::Gif {
+                mut encoder,
+                pending,
+                frames,
+                mut centisecs,
+            }) => {
+                let written = match pending {
+                    Some(pixels) => write_gif_frame(
+                        &mut encoder,
+                        &pixels,
+                        self.size,
+                        self.scale,
+                        frames,
+                        &mut centisecs,
+                    ),
+                    None => Ok(()),
+                };
+                written.and_then(|_| {
+                    encoder
+                        .into_inner()
+                        .and_then(|mut file| file.flush())
+                        .map_err(|err| err.to_string())
+                })
+            }
+            Some(Output::Raw(mut file)) => file.flush().map_err(|err| err.to_string()),
+            None => Ok(()),
+        };
+
+        if let Err(err) = result {
+            warn!("Couldn't finish {}: {}", self.path.to_string_lossy(), err);
+        }
+    }
+}
+
+/// Write an RGBA frame that ends after `end` emulated frames, at 60 frames a second.
+fn write_gif_frame(
+    encoder: &mut gif::Encoder<BufWriter<File>>,
+    rgba: &[u8],
+    size: (usize, usize),
+    scale: usize,
+    end: u32,
+    centisecs: &mut u32,
+) -> Result<(), String> {
+    let delay = (end * 100 / 60)
+        .saturating_sub(*centisecs)
+        .max(MIN_GIF_DELAY);
+    *centisecs += delay;
+
+    // Index the colours before scaling, there are usually only a handful
+    let mut colors: HashMap<[u8; 3], u8> = HashMap::new();
+    let mut palette = vec![];
+    let mut indices = Vec::with_capacity(size.0 * size.1);
+    for pix in rgba.chunks_exact(4) {
+        let color = [pix[0], pix[1], pix[2]];
+        let index = match colors.get(&color) {
+            Some(&index) => index,
+            None if colors.len() < 256 => {
+                let index = colors.len() as u8;
+                colors.insert(color, index);
+                palette.extend_from_slice(&color);
+                index
+            }
+            None => break,
+        };
+        indices.push(index);
+    }
+
+    let scaled_size = (size.0 * scale, size.1 * scale);
+    let (width, height) = (scaled_size.0 as u16, scaled_size.1 as u16);
+    let mut frame = if indices.len() == size.0 * size.1 {
+        let indices = resize(&indices, 1, size, scaled_size);
+        gif::Frame::from_palette_pixels(width, height, &indices, &palette, None)
+    } else {
+        // Too many colours, eg from MEGA-CHIP blending
+        let mut rgba = resize(rgba, 4, size, scaled_size);
+        gif::Frame::from_rgba_speed(width, height, &mut rgba, 10)
+    };
+    frame.delay = delay as u16;
+    encoder.write_frame(&frame).map_err(|err| err.to_string())
+}
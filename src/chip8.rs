@@ -1,20 +1,71 @@
-use crate::constants::{FLAGS_FNAME, HEIGHT, WIDTH};
+use crate::chip8x::Chip8X;
+use crate::constants::{HEIGHT, MEGA_HEIGHT, MEGA_WIDTH, WIDTH};
+use crate::flags::{FlagsStorage, MemoryFlags};
+use crate::fonts::FontSet;
+use crate::megachip::{BlendMode, MegaChip};
+use crate::profiles::Profile;
 
 use dynasmrt::{dynasm, DynasmApi, DynasmLabelApi, Assembler, ExecutableBuffer};
 use dynasmrt::x64::X64Relocation;
+use log::warn;
 use rand::rngs::ThreadRng;
 use rand::Rng;
+use serde::{Deserialize, Serialize};
 use std::cmp::min;
-use std::fs::File;
-use std::io::prelude::*;
 use std::mem;
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum Chip8System {
     CHIP8,
+    /// SCHIP before 1.1 added scrolling.
+    SCHIP10,
     LSCHIP,
     MSCHIP,
     XOCHIP,
+    MEGACHIP,
+    CHIP8X,
+    /// CHIP-8 with a 64x64 screen, for roms that start with `1260`.
+    CHIP8HIRES,
+}
+
+impl Chip8System {
+    /// Has SCHIP's opcodes for scrolling, hires, the big font, exit and flags.
+    pub fn has_schip(&self) -> bool {
+        matches!(
+            self,
+            Chip8System::SCHIP10
+                | Chip8System::LSCHIP
+                | Chip8System::MSCHIP
+                | Chip8System::XOCHIP
+                | Chip8System::MEGACHIP
+        )
+    }
+
+    /// Has SCHIP 1.1's scroll-down, scroll-left and scroll-right.
+    pub fn has_scroll(&self) -> bool {
+        self.has_schip() && *self != Chip8System::SCHIP10
+    }
+
+    /// Whether blocks can be compiled, rather than only using the interpreter.
+    pub fn jittable(&self) -> bool {
+        !matches!(
+            self,
+            Chip8System::MEGACHIP | Chip8System::CHIP8X | Chip8System::CHIP8HIRES
+        )
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Quirks {
+    pub vf_reset: bool,
+    pub memory: bool,
+    pub disp_wait: bool,
+    pub clipping: bool,
+    pub shifting: bool,
+    pub jumping: bool,
+    pub disp_wait_lores: bool,
+    pub scroll_full_lores: bool,
+    pub colors_16: bool,
 }
 
 struct Block {
@@ -25,10 +76,14 @@ pub struct Chip8 {
     pub mem: Box<[u8]>,
     pub vram: Box<[u8]>,
     pub i: u16,
+    /// Bits 16-23 of I, set by MEGA-CHIP's `01nn nnnn`.
+    pub i_hi: u8,
     pub pc: u16,
     pub regs: [u8; 16],
-    pub stack: [u16; 16], // 12 for chip-8, 16 for others
+    pub stack: [u16; 16],
     pub sp: u8,
+    /// How much of `stack` can be used, 12 for chip-8, 16 for others.
+    pub stack_depth: usize,
     pub halted: bool,
     halt_reg: usize,
     halt_wait_for_release: bool,
@@ -36,29 +91,37 @@ pub struct Chip8 {
     pub sound: u8,
     pub wait_vblank: bool,
     pub hires: bool,
+    /// Set by the 64x64 HiRes CHIP-8 boot code, which doubles lores' height.
+    pub hires64: bool,
     rng: ThreadRng,
     pub plane: u8,
     pub audio_buf: [u8; 16],
     pub pitch: u8,
+    pub mega: MegaChip,
+    pub c8x: Chip8X,
 
     pub paused: bool,
     pub keys_held: [bool; 16],
+    pub flags: Box<dyn FlagsStorage>,
 
+    pub profile_name: String,
     pub system: Chip8System,
-    pub quirk_vf_reset: bool,
-    pub quirk_memory: bool,
-    pub quirk_disp_wait: bool,
-    pub quirk_clipping: bool,
-    pub quirk_shifting: bool,
-    pub quirk_jumping: bool,
-    pub quirk_disp_wait_lores: bool,
-    pub quirk_scroll_full_lores: bool,
-    pub quirk_16_colors: bool,
+    /// Where roms are loaded and started.
+    pub start_address: u16,
+    /// Size of the loaded rom, telling its data apart from free memory.
+    pub rom_len: usize,
+    /// Addressable memory in bytes.
+    pub mem_size: usize,
+    pub quirks: Quirks,
+    pub font: FontSet,
 
     mems: Box<[Option<Block>]>,
     try_jit: Box<[bool]>,
     inf_loop: bool,
     jit_cyc: i32,
+    break_ranges: Vec<(u16, u16)>,
+    /// Addresses in `break_ranges`, which compiled blocks stop before.
+    break_addrs: Box<[bool]>,
 }
 
 macro_rules! offset {
@@ -191,10 +254,12 @@ impl Chip8 {
             mem: vec!(0; 0x10000).into_boxed_slice(),
             vram: vec!(0; WIDTH * HEIGHT).into_boxed_slice(),
             i: 0,
+            i_hi: 0,
             pc: 0x200,
             regs: [0; 16],
             stack: [0; 16],
             sp: 0,
+            stack_depth: 16,
             halted: false,
             halt_reg: 0,
             halt_wait_for_release: false,
@@ -202,174 +267,191 @@ impl Chip8 {
             sound: 0,
             wait_vblank: true,
             hires: false,
+            hires64: false,
             rng: rand::thread_rng(),
             plane: 1,
             audio_buf: [0; 16],
             pitch: 0,
+            mega: MegaChip::new(),
+            c8x: Chip8X::new(),
 
             paused: true,
             keys_held: [false; 16],
+            flags: Box::new(MemoryFlags::new()),
 
+            profile_name: String::new(),
             system: Chip8System::CHIP8,
-            quirk_vf_reset: false,
-            quirk_memory: false,
-            quirk_disp_wait: false,
-            quirk_clipping: false,
-            quirk_shifting: false,
-            quirk_jumping: false,
-            quirk_disp_wait_lores: false,
-            quirk_scroll_full_lores: false,
-            quirk_16_colors: true,
+            start_address: 0x200,
+            rom_len: 0,
+            mem_size: 0x10000,
+            quirks: Quirks {
+                vf_reset: false,
+                memory: false,
+                disp_wait: false,
+                clipping: false,
+                shifting: false,
+                jumping: false,
+                disp_wait_lores: false,
+                scroll_full_lores: false,
+                colors_16: true,
+            },
+            font: FontSet::Octo,
 
             mems: mems.into_boxed_slice(),
             try_jit: vec!(true; 0x4000).into_boxed_slice(),
             inf_loop: false,
             jit_cyc: 0,
+            break_ranges: vec![],
+            break_addrs: vec![false; 0x10000].into_boxed_slice(),
         };
 
-        let font: [u8; 0x50] = [
-            0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
-            0x20, 0x60, 0x20, 0x20, 0x70, // 1
-            0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
-            0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
-            0x90, 0x90, 0xF0, 0x10, 0x10, // 4
-            0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
-            0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
-            0xF0, 0x10, 0x20, 0x40, 0x40, // 7
-            0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
-            0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
-            0xF0, 0x90, 0xF0, 0x90, 0x90, // A
-            0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
-            0xF0, 0x80, 0x80, 0x80, 0xF0, // C
-            0xE0, 0x90, 0x90, 0x90, 0xE0, // D
-            0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
-            0xF0, 0x80, 0xF0, 0x80, 0x80, // F
-        ];
-
-        for (i, b) in font.iter().enumerate() {
-            ret.mem[0x50 + i] = *b;
-        }
+        ret.set_profile(&Profile::new());
+
+        ret
+    }
 
-        let font: [u8; 0xa0] = [
-            0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
-            0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
-            0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
-            0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
-            0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
-            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
-            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
-            0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
-            0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
-            0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
-            0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
-            0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
-            0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
-            0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
-            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
-            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
-        ];
-
-        for (i, b) in font.iter().enumerate() {
-            ret.mem[0xa0 + i] = *b;
+    pub fn set_profile(&mut self, profile: &Profile) {
+        self.profile_name = profile.name.clone();
+        self.system = profile.system;
+        self.start_address = profile.start_address;
+        self.set_mem_size(profile.mem_size);
+        self.stack_depth = min(profile.stack_depth, self.stack.len());
+        self.quirks = profile.quirks;
+        self.font = profile.font;
+        self.font.load(&mut self.mem);
+    }
+
+    pub fn set_mem_size(&mut self, mem_size: usize) {
+        self.mem_size = mem_size;
+        // Smaller sizes keep the 64k buffer, but addresses wrap at the size
+        if self.mem.len() < mem_size {
+            let mut mem = self.mem.to_vec();
+            mem.resize(mem_size, 0);
+            self.mem = mem.into_boxed_slice();
         }
+    }
 
-        ret.set_system(Chip8System::XOCHIP);
+    /// The profile the current settings make up, including any changed quirks.
+    pub fn profile(&self) -> Profile {
+        Profile {
+            name: self.profile_name.clone(),
+            system: self.system,
+            start_address: self.start_address,
+            mem_size: self.mem_size,
+            stack_depth: self.stack_depth,
+            quirks: self.quirks,
+            font: self.font,
+        }
+    }
 
-        ret
+    /// Take over the system and quirk settings of another instance, eg across a reset.
+    pub fn copy_quirks(&mut self, other: &Chip8) {
+        self.set_profile(&other.profile());
     }
 
-    pub fn set_system(&mut self, system: Chip8System) {
-        match system {
-            Chip8System::CHIP8 => {
-                self.quirk_vf_reset = true;
-                self.quirk_memory = true;
-                self.quirk_disp_wait = true;
-                self.quirk_clipping = true;
-                self.quirk_shifting = false;
-                self.quirk_jumping = false;
-                self.quirk_disp_wait_lores = true;
-                self.quirk_scroll_full_lores = true;
-            }
-            Chip8System::LSCHIP => {
-                self.quirk_vf_reset = false;
-                self.quirk_memory = false;
-                self.quirk_disp_wait = true;
-                self.quirk_clipping = true;
-                self.quirk_shifting = true;
-                self.quirk_jumping = true;
-                self.quirk_disp_wait_lores = true;
-                self.quirk_scroll_full_lores = false;
-            }
-            Chip8System::MSCHIP => {
-                self.quirk_vf_reset = false;
-                self.quirk_memory = false;
-                self.quirk_disp_wait = true;
-                self.quirk_clipping = true;
-                self.quirk_shifting = true;
-                self.quirk_jumping = true;
-                self.quirk_disp_wait_lores = false;
-                self.quirk_scroll_full_lores = true;
-            }
-            Chip8System::XOCHIP => {
-                self.quirk_vf_reset = false;
-                self.quirk_memory = true;
-                self.quirk_disp_wait = true;
-                self.quirk_clipping = false;
-                self.quirk_shifting = false;
-                self.quirk_jumping = false;
-                self.quirk_disp_wait_lores = false;
-                self.quirk_scroll_full_lores = true;
+    pub fn load_rom(&mut self, mut rom: Vec<u8>) {
+        let start = self.start_address as usize;
+        if self.system != Chip8System::MEGACHIP {
+            let room = self.addressable().saturating_sub(start);
+            if rom.len() > room {
+                warn!(
+                    "The rom doesn't fit in memory, leaving out its last {} bytes",
+                    rom.len() - room
+                );
+                rom.truncate(room);
             }
         }
-        self.system = system;
+        // MEGA-CHIP roms can be much larger than the usual 64k
+        if start + rom.len() > self.mem.len() {
+            let mut mem = self.mem.to_vec();
+            mem.resize(start + rom.len(), 0);
+            self.mem = mem.into_boxed_slice();
+        }
+        for (i, byte) in rom.iter().enumerate() {
+            self.mem[start + i] = *byte;
+        }
+        self.rom_len = rom.len();
+        self.pc = start as u16;
+
+        // HiRes CHIP-8 roms start by jumping to the rest of its boot code, which
+        // enables 64x64 mode and starts the program at 0x2c0. This is per rom, so the
+        // chosen system stays as it is for the next one
+        self.hires64 = matches!(self.system, Chip8System::CHIP8 | Chip8System::CHIP8HIRES)
+            && rom.starts_with(&[0x12, 0x60]);
+        if self.hires64 {
+            self.pc = 0x2c0;
+        }
     }
 
-    pub fn draw(&self, frame: &mut [u8]) {
-        for (c, pix) in self.vram.iter().zip(frame.chunks_exact_mut(4)) {
-            let color = match self.quirk_16_colors {
-                true => match c {
-                    0x0 => [0x00, 0x00, 0x00, 0xff],
-                    0x1 => [0xff, 0xff, 0xff, 0xff],
-                    0x2 => [0xaa, 0xaa, 0xaa, 0xff],
-                    0x3 => [0x55, 0x55, 0x55, 0xff],
-                    0x4 => [0xff, 0x00, 0x00, 0xff],
-                    0x5 => [0x00, 0xff, 0x00, 0xff],
-                    0x6 => [0x00, 0x00, 0xff, 0xff],
-                    0x7 => [0xff, 0xff, 0x00, 0xff],
-                    0x8 => [0x88, 0x00, 0x00, 0xff],
-                    0x9 => [0x00, 0x88, 0x00, 0xff],
-                    0xa => [0x00, 0x00, 0x88, 0xff],
-                    0xb => [0x88, 0x88, 0x00, 0xff],
-                    0xc => [0xff, 0x00, 0xff, 0xff],
-                    0xd => [0x00, 0xff, 0xff, 0xff],
-                    0xe => [0x88, 0x00, 0x88, 0xff],
-                    0xf => [0x00, 0x88, 0x88, 0xff],
-                    _ => panic!("Invalid color"),
-                },
-                false => match c & 3 {
-                    0 => [0x22, 0x22, 0x22, 0xff],
-                    1 => [0xff, 0xff, 0xff, 0xff],
-                    2 => [0x00, 0x44, 0xaa, 0xff],
-                    3 => [0xaa, 0x55, 0x00, 0xff],
-                    _ => panic!("Invalid color"),
-                },
-            };
-            pix.copy_from_slice(&color);
+    /// Whether HiRes CHIP-8's boot code opcodes run, by profile or for a HiRes rom.
+    pub fn has_hires_boot(&self) -> bool {
+        self.system == Chip8System::CHIP8HIRES || self.hires64
+    }
+
+    /// Where the rom's own code starts, after any boot code.
+    pub fn entry_point(&self) -> u16 {
+        if self.hires64 {
+            0x2c0
+        } else {
+            self.start_address
         }
     }
 
-    pub fn load_rom(&mut self, rom: Vec<u8>) {
-        for (i, byte) in rom.iter().enumerate() {
-            self.mem[0x200 + i] = *byte;
+    /// Width and height in vram of each pixel drawn.
+    pub fn pixel_size(&self) -> (usize, usize) {
+        if self.hires {
+            (1, 1)
+        } else if self.hires64 {
+            (2, 1)
+        } else {
+            (2, 2)
+        }
+    }
+
+    /// The largest screen this system can show, eg for sizing recordings.
+    pub fn max_screen_size(&self) -> (usize, usize) {
+        if self.system == Chip8System::MEGACHIP {
+            (MEGA_WIDTH, MEGA_HEIGHT)
+        } else {
+            (WIDTH, HEIGHT)
         }
     }
 
+    /// Size of the memory addresses reach, past which they wrap around.
+    fn addressable(&self) -> usize {
+        min(self.mem_size, 0x10000)
+    }
+
+    /// Index into `mem` of an address, wrapped to the addressable memory. MEGA-CHIP's
+    /// 24-bit addresses reach past it into large roms, so those aren't wrapped.
+    fn mem_index(&self, addr: usize) -> usize {
+        if self.system == Chip8System::MEGACHIP {
+            addr
+        } else {
+            addr % self.addressable()
+        }
+    }
+
+    /// I including MEGA-CHIP's high byte.
+    pub fn i_addr(&self) -> usize {
+        ((self.i_hi as usize) << 16) | self.i as usize
+    }
+
+    /// The opcode at an address, read the way `step` wraps it.
+    pub fn op_at(&self, addr: u16) -> u16 {
+        let hi = self.mem[self.mem_index(addr as usize)] as u16;
+        let lo = self.mem[self.mem_index(addr as usize + 1)] as u16;
+        (hi << 8) | lo
+    }
+
     fn skip_ins(&mut self) {
         if self.system == Chip8System::XOCHIP
             && self.mem[self.pc as usize] == 0xf0
             && self.mem[self.pc as usize + 1] == 0x00
         {
             self.pc += 4;
+        } else if self.system == Chip8System::MEGACHIP && self.mem[self.pc as usize] == 0x01 {
+            self.pc += 4;
         } else {
             self.pc += 2;
         }
@@ -459,7 +541,9 @@ impl Chip8 {
             _ => (),
         }
 
-        ret
+        ret.into_iter()
+            .map(|(addr, is_read)| (self.mem_index(addr as usize) as u16, is_read))
+            .collect()
     }
 
     fn compile_ins(&mut self, ops: &mut Assembler<X64Relocation>, pc: u16) -> u16 {
@@ -496,13 +580,19 @@ impl Chip8 {
                         );
                     }
                     0x0ee => {
-                        // return
+                        // return, wrapping below the bottom of the stack like `step`
                         let sp_offs = offset!(Chip8, sp);
                         let stack_offs = offset!(Chip8, stack);
+                        let depth_offs = offset!(Chip8, stack_depth);
                         let pc_offs = offset!(Chip8, pc);
                         my_dynasm!(ops
-                            ; sub BYTE [rdi+sp_offs as i32], 1
                             ; movzx rax, BYTE [rdi+sp_offs as i32]
+                            ; sub rax, 1
+                            ; jae >no_wrap
+                            ; mov rax, QWORD [rdi+depth_offs as i32]
+                            ; sub rax, 1
+                            ; no_wrap:
+                            ; mov BYTE [rdi+sp_offs as i32], al
                             ; mov ax, WORD [rdi+rax*2+stack_offs as i32]
                             ; mov WORD [rdi+pc_offs as i32], ax
                             ; add r9, self.jit_cyc
@@ -551,14 +641,20 @@ impl Chip8 {
                 return 0xffff;
             }
             0x2 => {
-                // call nnn
+                // call nnn, wrapping past the top of the stack like `step`
                 let sp_offs = offset!(Chip8, sp);
                 let stack_offs = offset!(Chip8, stack);
+                let depth_offs = offset!(Chip8, stack_depth);
                 let pc_offs = offset!(Chip8, pc);
                 my_dynasm!(ops
                     ; movzx rax, BYTE [rdi+sp_offs as i32]
                     ; mov WORD [rdi+rax*2+stack_offs as i32], pc as i16
-                    ; add BYTE [rdi+sp_offs as i32], 1
+                    ; add rax, 1
+                    ; cmp rax, QWORD [rdi+depth_offs as i32]
+                    ; jb >no_wrap
+                    ; xor eax, eax
+                    ; no_wrap:
+                    ; mov BYTE [rdi+sp_offs as i32], al
                     ; mov WORD [rdi+pc_offs as i32], nnn as i16
                     ; add r9, self.jit_cyc
                     ; jmp >end
@@ -568,7 +664,7 @@ impl Chip8 {
             0x3 => {
                 // if vx != nn then
                 let rx_offs = offset!(Chip8, regs) + x as usize;
-                if self.jittable(pc) {
+                if self.jittable_in_block(pc) {
                     my_dynasm!(ops
                         ; cmp BYTE [rdi+rx_offs as i32], nn as i8
                         ; je >branch
@@ -585,7 +681,7 @@ impl Chip8 {
             0x4 => {
                 // if vx == nn then
                 let rx_offs = offset!(Chip8, regs) + x as usize;
-                if self.jittable(pc) {
+                if self.jittable_in_block(pc) {
                     my_dynasm!(ops
                         ; cmp BYTE [rdi+rx_offs as i32], nn as i8
                         ; jne >branch
@@ -605,7 +701,7 @@ impl Chip8 {
                         // if vx != vy then
                         let rx_offs = offset!(Chip8, regs) + x as usize;
                         let ry_offs = offset!(Chip8, regs) + y as usize;
-                        if self.jittable(pc) {
+                        if self.jittable_in_block(pc) {
                             my_dynasm!(ops
                                 ; mov al, BYTE [rdi+ry_offs as i32]
                                 ; cmp BYTE [rdi+rx_offs as i32], al
@@ -808,7 +904,7 @@ impl Chip8 {
                     // if vx == vy then
                     let rx_offs = offset!(Chip8, regs) + x as usize;
                     let ry_offs = offset!(Chip8, regs) + y as usize;
-                    if self.jittable(pc) {
+                    if self.jittable_in_block(pc) {
                         my_dynasm!(ops
                             ; mov al, BYTE [rdi+ry_offs as i32]
                             ; cmp BYTE [rdi+rx_offs as i32], al
@@ -885,7 +981,7 @@ impl Chip8 {
                         // if vx -key then
                         let rx_offs = offset!(Chip8, regs) + x as usize;
                         let keys_held_offs = offset!(Chip8, keys_held);
-                        if self.jittable(pc) {
+                        if self.jittable_in_block(pc) {
                             my_dynasm!(ops
                                 ; movzx rsi, BYTE [rdi+rx_offs as i32]
                                 ; add rsi, keys_held_offs as i32
@@ -907,7 +1003,7 @@ impl Chip8 {
                         // if vx key then
                         let rx_offs = offset!(Chip8, regs) + x as usize;
                         let keys_held_offs = offset!(Chip8, keys_held);
-                        if self.jittable(pc) {
+                        if self.jittable_in_block(pc) {
                             my_dynasm!(ops
                                 ; movzx rsi, BYTE [rdi+rx_offs as i32]
                                 ; add rsi, keys_held_offs as i32
@@ -1103,11 +1199,14 @@ impl Chip8 {
     }
 
     fn jittable(&self, pc: u16) -> bool {
-        if !self.try_jit[pc as usize] {
+        if !self.try_jit[pc as usize] || pc as usize + 1 >= self.addressable() {
             return false;
         }
 
         let op = ((self.mem[pc as usize] as u16) << 8) | (self.mem[pc as usize + 1] as u16);
+        // Compiled code doesn't wrap addresses, so with less memory what uses I is
+        // interpreted
+        let wraps = self.addressable() < 0x10000;
 
         let n0 = op >> 12;
         // let x = (op >> 8) & 0xf;
@@ -1131,17 +1230,45 @@ impl Chip8 {
             }
             0xf => {
                 match nn {
+                    0x1e | 0x33 | 0x55 | 0x65 if wraps => false,
                     0x00 | 0x07 | 0x0a | 0x15 | 0x18 | 0x1e | 0x33 | 0x55 | 0x65 => true,
                     _ => false
                 }
             }
+            0xa | 0xd if wraps => false,
             0x1..=0x4 | 0x6..=0xa | 0xd..=0xe => true,
             _ => false,
         }
     }
 
+    /// Like `jittable`, for instructions after a block's first. Blocks end before
+    /// breakpoints, so they're checked exactly.
+    fn jittable_in_block(&self, pc: u16) -> bool {
+        !self.break_addrs[pc as usize] && self.jittable(pc)
+    }
+
+    /// Make compiled blocks end before these address ranges. Blocks compiled with
+    /// different ranges are thrown away.
+    pub fn set_break_ranges(&mut self, ranges: Vec<(u16, u16)>) {
+        if ranges == self.break_ranges {
+            return;
+        }
+        self.break_addrs.fill(false);
+        for &(start, end) in &ranges {
+            for addr in start..=end {
+                self.break_addrs[addr as usize] = true;
+            }
+        }
+        self.break_ranges = ranges;
+        for block in self.mems.iter_mut() {
+            *block = None;
+        }
+    }
+
     pub fn run_block(&mut self) -> i32 {
-        if self.halted || !self.try_jit[self.pc as usize] {
+        // HiRes roms' boot code needs the interpreter, whatever the system
+        let jittable = self.system.jittable() && !self.hires64;
+        if self.halted || !jittable || !self.try_jit[self.pc as usize] {
             self.step();
             return 1;
         }
@@ -1175,7 +1302,7 @@ impl Chip8 {
                     if ret_pc == 0xffff {
                         break;
                     }
-                    if !self.jittable(ret_pc) {
+                    if !self.jittable_in_block(ret_pc) {
                         break;
                     }
                 }
@@ -1264,11 +1391,13 @@ impl Chip8 {
             return;
         }
 
+        // pc wraps around the addressable memory too
+        self.pc = self.mem_index(self.pc as usize) as u16;
         let byte = self.mem[self.pc as usize];
         self.pc += 1;
         let mut op = (byte as u16) << 8;
 
-        let byte = self.mem[self.pc as usize];
+        let byte = self.mem[self.mem_index(self.pc as usize)];
         self.pc += 1;
         op |= byte as u16;
 
@@ -1282,15 +1411,41 @@ impl Chip8 {
         match n0 {
             0x0 => {
                 match nnn {
+                    0x010 => {
+                        // megaoff
+                        if self.system != Chip8System::MEGACHIP {
+                            return;
+                        }
+                        self.mega.enabled = false;
+                    }
+                    0x011 => {
+                        // megaon
+                        if self.system != Chip8System::MEGACHIP {
+                            return;
+                        }
+                        self.mega.enabled = true;
+                        self.mega.present();
+                    }
+                    0x0b0..=0x0bf => {
+                        // scroll-up n (MEGA-CHIP)
+                        if !self.mega.enabled {
+                            return;
+                        }
+                        self.mega.scroll(0, -(n as isize));
+                    }
                     0x0c0..=0x0cf => {
                         // scroll-down n
-                        if self.system == Chip8System::CHIP8 {
+                        if !self.system.has_scroll() {
+                            return;
+                        }
+                        if self.mega.enabled {
+                            self.mega.scroll(0, n as isize);
                             return;
                         }
                         if n == 0 {
                             return;
                         }
-                        let scroll_times = if !self.hires && self.quirk_scroll_full_lores {
+                        let scroll_times = if !self.hires && self.quirks.scroll_full_lores {
                             2
                         } else {
                             1
@@ -1318,7 +1473,7 @@ impl Chip8 {
                         if n == 0 {
                             return;
                         }
-                        let scroll_times = if !self.hires && self.quirk_scroll_full_lores {
+                        let scroll_times = if !self.hires && self.quirks.scroll_full_lores {
                             2
                         } else {
                             1
@@ -1339,24 +1494,45 @@ impl Chip8 {
                             }
                         }
                     }
+                    0x230 if self.has_hires_boot() => {
+                        // clear, in HiRes CHIP-8's boot code
+                        self.vram.fill(0);
+                    }
+                    0x2a0 if self.system == Chip8System::CHIP8X => {
+                        // next-background
+                        self.c8x.next_background();
+                    }
                     0x0e0 => {
                         // clear
+                        if self.mega.enabled {
+                            // MEGA-CHIP shows a frame when it's cleared, so wait for the next one
+                            self.mega.present();
+                            self.wait_vblank = true;
+                            return;
+                        }
                         let mask = 0xff - self.plane;
                         for i in 0..WIDTH * HEIGHT {
                             self.vram[i] &= mask;
                         }
                     }
                     0x0ee => {
-                        // return
-                        self.sp -= 1;
+                        // return, wrapping to the top of the stack when it's empty
+                        self.sp = match self.sp {
+                            0 => self.stack_depth as u8 - 1,
+                            sp => sp - 1,
+                        };
                         self.pc = self.stack[self.sp as usize];
                     }
                     0x0fb => {
                         // scroll-right
-                        if self.system == Chip8System::CHIP8 {
+                        if !self.system.has_scroll() {
                             return;
                         }
-                        let scroll_times = if !self.hires && self.quirk_scroll_full_lores {
+                        if self.mega.enabled {
+                            self.mega.scroll(4, 0);
+                            return;
+                        }
+                        let scroll_times = if !self.hires && self.quirks.scroll_full_lores {
                             2
                         } else {
                             1
@@ -1379,10 +1555,14 @@ impl Chip8 {
                     }
                     0x0fc => {
                         // scroll-left
-                        if self.system == Chip8System::CHIP8 {
+                        if !self.system.has_scroll() {
                             return;
                         }
-                        let scroll_times = if !self.hires && self.quirk_scroll_full_lores {
+                        if self.mega.enabled {
+                            self.mega.scroll(-4, 0);
+                            return;
+                        }
+                        let scroll_times = if !self.hires && self.quirks.scroll_full_lores {
                             2
                         } else {
                             1
@@ -1405,25 +1585,28 @@ impl Chip8 {
                     }
                     0x0fd => {
                         // exit
-                        if self.system == Chip8System::CHIP8 {
+                        if !self.system.has_schip() {
                             return;
                         }
                         panic!("Exit");
                     }
                     0x0fe => {
                         // lores
-                        if self.system == Chip8System::CHIP8 {
+                        if !self.system.has_schip() {
                             return;
                         }
                         self.hires = false;
                     }
                     0x0ff => {
                         // hires
-                        if self.system == Chip8System::CHIP8 {
+                        if !self.system.has_schip() {
                             return;
                         }
                         self.hires = true;
                     }
+                    0x100..=0x9ff if self.system == Chip8System::MEGACHIP => {
+                        self.step_megachip(op);
+                    }
                     _ => panic!("Unknown opcode ${:04x}", op),
                 }
             }
@@ -1432,9 +1615,9 @@ impl Chip8 {
                 self.pc = nnn;
             }
             0x2 => {
-                // call nnn
+                // call nnn, wrapping to the bottom of the stack when it's full
                 self.stack[self.sp as usize] = self.pc;
-                self.sp += 1;
+                self.sp = ((self.sp as usize + 1) % self.stack_depth) as u8;
                 self.pc = nnn;
             }
             0x3 => {
@@ -1457,6 +1640,16 @@ impl Chip8 {
                             self.skip_ins();
                         }
                     }
+                    1 => {
+                        // vx += vy nibbles, with each nibble wrapping at 8
+                        if self.system != Chip8System::CHIP8X {
+                            return;
+                        }
+                        let (vx, vy) = (self.regs[x as usize], self.regs[y as usize]);
+                        let high = ((vx & 0x70) + (vy & 0x70)) & 0x70;
+                        let low = ((vx & 7) + (vy & 7)) & 7;
+                        self.regs[x as usize] = high | low;
+                    }
                     2 => {
                         // save vx - vy
                         if self.system != Chip8System::XOCHIP {
@@ -1464,7 +1657,8 @@ impl Chip8 {
                         }
                         let mut i = self.i as usize;
                         for reg in (x as usize)..=(y as usize) {
-                            self.mem[i] = self.regs[reg];
+                            let index = self.mem_index(i);
+                            self.mem[index] = self.regs[reg];
                             i += 1;
                         }
                     }
@@ -1475,7 +1669,7 @@ impl Chip8 {
                         }
                         let mut i = self.i as usize;
                         for reg in (x as usize)..=(y as usize) {
-                            self.regs[reg] = self.mem[i];
+                            self.regs[reg] = self.mem[self.mem_index(i)];
                             i += 1;
                         }
                     }
@@ -1499,21 +1693,21 @@ impl Chip8 {
                     0x1 => {
                         // vx |= vy
                         self.regs[x as usize] |= self.regs[y as usize];
-                        if self.quirk_vf_reset {
+                        if self.quirks.vf_reset {
                             self.regs[0xf] = 0;
                         }
                     }
                     0x2 => {
                         // vx &= vy
                         self.regs[x as usize] &= self.regs[y as usize];
-                        if self.quirk_vf_reset {
+                        if self.quirks.vf_reset {
                             self.regs[0xf] = 0;
                         }
                     }
                     0x3 => {
                         // vx ^= vy
                         self.regs[x as usize] ^= self.regs[y as usize];
-                        if self.quirk_vf_reset {
+                        if self.quirks.vf_reset {
                             self.regs[0xf] = 0;
                         }
                     }
@@ -1533,7 +1727,7 @@ impl Chip8 {
                     }
                     0x6 => {
                         // vx >>= vy
-                        let idx = if self.quirk_shifting {x as usize} else {y as usize};
+                        let idx = if self.quirks.shifting {x as usize} else {y as usize};
                         let carry = self.regs[idx] & 1;
                         self.regs[x as usize] = self.regs[idx] >> 1;
                         self.regs[0xf] = carry;
@@ -1547,7 +1741,7 @@ impl Chip8 {
                     }
                     0xe => {
                         // vx <<= vy
-                        let idx = if self.quirk_shifting {x as usize} else {y as usize};
+                        let idx = if self.quirks.shifting {x as usize} else {y as usize};
                         let carry = self.regs[idx] >> 7;
                         self.regs[x as usize] = self.regs[idx] << 1;
                         self.regs[0xf] = carry;
@@ -1565,11 +1759,26 @@ impl Chip8 {
             }
             0xa => {
                 // i := nnn
-                self.i = nnn;
+                self.i = self.mem_index(nnn as usize) as u16;
+                self.i_hi = 0;
             }
             0xb => {
+                if self.system == Chip8System::CHIP8X {
+                    let vx = self.regs[x as usize];
+                    let vx1 = self.regs[(x as usize + 1) & 0xf];
+                    let color = self.regs[y as usize];
+                    if n == 0 {
+                        // zone-color vx vy
+                        self.c8x.color_zones(vx, vx1, color);
+                    } else {
+                        // row-color vx vy n
+                        self.c8x.color_rows(vx, vx1, n as u8, color);
+                    }
+                    return;
+                }
+
                 // jump0 nnn
-                if self.quirk_jumping {
+                if self.quirks.jumping {
                     self.pc = nnn + self.regs[x as usize] as u16;
                 } else {
                     self.pc = nnn + self.regs[0] as u16;
@@ -1581,15 +1790,29 @@ impl Chip8 {
             }
             0xd => {
                 // sprite vx vy N
+                if self.mega.enabled {
+                    let x = self.regs[x as usize] as usize;
+                    let y = self.regs[y as usize] as usize;
+                    let addr = self.i_addr();
+
+                    // The font is still 1-bit, everything else uses the sprite size registers
+                    let collided = if addr < 0x100 {
+                        self.mega.draw_bits(&self.mem, addr, x, y, n as usize)
+                    } else {
+                        self.mega.draw_sprite(&self.mem, addr, x, y)
+                    };
+                    self.regs[0xf] = if collided { 1 } else { 0 };
+                    return;
+                }
+
                 let mut xord = false;
                 let mut startx = self.regs[x as usize] as usize;
                 let mut starty = self.regs[y as usize] as usize;
 
-                // Emulate chip-8 as if schip/xo-chip
-                if !self.hires {
-                    startx *= 2;
-                    starty *= 2;
-                }
+                // Emulate lower resolutions as if schip/xo-chip hires
+                let (pixel_width, pixel_height) = self.pixel_size();
+                startx *= pixel_width;
+                starty *= pixel_height;
 
                 startx %= WIDTH;
                 starty %= HEIGHT;
@@ -1606,7 +1829,7 @@ impl Chip8 {
                             let mut drawx = startx;
 
                             for _ in 0..byte_width {
-                                let mut byte = self.mem[src + i];
+                                let mut byte = self.mem[self.mem_index(src + i)];
                                 i += 1;
 
                                 let mut j: usize = 0;
@@ -1614,7 +1837,7 @@ impl Chip8 {
                                     let bit_set = (byte & 0x80) != 0;
                                     byte <<= 1;
 
-                                    if self.quirk_clipping && drawx >= WIDTH {
+                                    if self.quirks.clipping && drawx >= WIDTH {
                                         break;
                                     }
 
@@ -1622,36 +1845,26 @@ impl Chip8 {
                                     drawx %= WIDTH;
                                     let draw_offs = drawy * WIDTH + drawx;
                                     if bit_set {
-                                        if self.hires {
-                                            if (self.vram[draw_offs] & planeid) != 0 {
-                                                xord = true;
-                                            }
-                                            self.vram[draw_offs] ^= planeid;
-                                        } else {
-                                            // plot 2x2
-                                            if ((self.vram[draw_offs] & planeid)
-                                                + (self.vram[draw_offs + 1] & planeid)
-                                                + (self.vram[draw_offs + WIDTH] & planeid)
-                                                + (self.vram[draw_offs + WIDTH + 1] & planeid))
-                                                != 0
-                                            {
-                                                xord = true;
+                                        // plot a pixel_width x pixel_height block
+                                        for py in 0..pixel_height {
+                                            for px in 0..pixel_width {
+                                                let offs = draw_offs + py * WIDTH + px;
+                                                if (self.vram[offs] & planeid) != 0 {
+                                                    xord = true;
+                                                }
+                                                self.vram[offs] ^= planeid;
                                             }
-                                            self.vram[draw_offs] ^= planeid;
-                                            self.vram[draw_offs + 1] ^= planeid;
-                                            self.vram[draw_offs + WIDTH] ^= planeid;
-                                            self.vram[draw_offs + WIDTH + 1] ^= planeid;
                                         }
                                     }
 
-                                    drawx += if self.hires { 1 } else { 2 };
+                                    drawx += pixel_width;
                                     j += 1;
                                 }
                             }
 
-                            drawy += if self.hires { 1 } else { 2 };
+                            drawy += pixel_height;
                             if drawy == HEIGHT {
-                                if self.quirk_clipping {
+                                if self.quirks.clipping {
                                     break;
                                 }
                                 drawy = 0;
@@ -1664,7 +1877,7 @@ impl Chip8 {
                 }
 
                 self.regs[0xf] = if xord { 1 } else { 0 };
-                if self.quirk_disp_wait && !self.hires && self.quirk_disp_wait_lores {
+                if self.quirks.disp_wait && !self.hires && self.quirks.disp_wait_lores {
                     self.wait_vblank = true;
                 }
             }
@@ -1684,6 +1897,13 @@ impl Chip8 {
                             self.skip_ins();
                         }
                     }
+                    0xf2 if self.system == Chip8System::CHIP8X => {
+                        // if vx -key2 then, where there's no 2nd keypad to press
+                    }
+                    0xf5 if self.system == Chip8System::CHIP8X => {
+                        // if vx key2 then
+                        self.skip_ins();
+                    }
                     _ => panic!("Unknown opcode ${:04x}", op),
                 }
             }
@@ -1718,7 +1938,7 @@ impl Chip8 {
                                 return;
                             }
                             for i in 0..16 {
-                                self.audio_buf[i] = self.mem[self.i as usize + i];
+                                self.audio_buf[i] = self.mem[self.mem_index(self.i as usize + i)];
                             }
                             // todo: audio
                         }
@@ -1745,21 +1965,25 @@ impl Chip8 {
                     }
                     0x1e => {
                         // i += vx
-                        self.i += self.regs[x as usize] as u16;
-                        if self.system != Chip8System::XOCHIP {
-                            self.i &= 0xfff;
+                        if self.system == Chip8System::MEGACHIP {
+                            let addr = self.i_addr() + self.regs[x as usize] as usize;
+                            self.i = addr as u16;
+                            self.i_hi = (addr >> 16) as u8;
+                            return;
                         }
+                        let addr = self.i as usize + self.regs[x as usize] as usize;
+                        self.i = self.mem_index(addr) as u16;
                     }
                     0x29 => {
                         // i := hex vx
-                        self.i = self.regs[x as usize] as u16 * 5 + 0x50;
+                        self.i = self.font.hex_addr(self.regs[x as usize]);
                     }
                     0x30 => {
                         // i := bighex vx
-                        if self.system == Chip8System::CHIP8 {
+                        if !self.system.has_schip() {
                             return;
                         }
-                        self.i = self.regs[x as usize] as u16 * 10 + 0xa0;
+                        self.i = self.font.bighex_addr(self.regs[x as usize]);
                     }
                     0x33 => {
                         // bcd vx
@@ -1768,9 +1992,10 @@ impl Chip8 {
                         value %= 100;
                         let t = value / 10;
                         let u = value % 10;
-                        self.mem[self.i as usize] = h;
-                        self.mem[self.i as usize + 1] = t;
-                        self.mem[self.i as usize + 2] = u;
+                        for (offset, digit) in [h, t, u].into_iter().enumerate() {
+                            let index = self.mem_index(self.i as usize + offset);
+                            self.mem[index] = digit;
+                        }
                     }
                     0x3a => {
                         // pitch := vx
@@ -1782,24 +2007,25 @@ impl Chip8 {
                     0x55 => {
                         // save vx
                         for i in 0..=(x as usize) {
-                            self.mem[self.i as usize + i] = self.regs[i];
+                            let index = self.mem_index(self.i as usize + i);
+                            self.mem[index] = self.regs[i];
                         }
-                        if self.quirk_memory {
-                            self.i += x + 1;
+                        if self.quirks.memory {
+                            self.i = self.mem_index(self.i as usize + x as usize + 1) as u16;
                         }
                     }
                     0x65 => {
                         // load vx
                         for i in 0..=(x as usize) {
-                            self.regs[i] = self.mem[self.i as usize + i];
+                            self.regs[i] = self.mem[self.mem_index(self.i as usize + i)];
                         }
-                        if self.quirk_memory {
-                            self.i += x + 1;
+                        if self.quirks.memory {
+                            self.i = self.mem_index(self.i as usize + x as usize + 1) as u16;
                         }
                     }
                     0x75 => {
                         // saveflags vx
-                        if self.system == Chip8System::CHIP8 {
+                        if !self.system.has_schip() {
                             return;
                         }
                         let x = if self.system == Chip8System::XOCHIP {
@@ -1808,33 +2034,21 @@ impl Chip8 {
                             min(x, 7)
                         };
 
-                        // Get the current 16 flags, if the file exists
-                        let mut buffer = [0; 16];
-                        match File::open(FLAGS_FNAME) {
-                            Ok(mut file) => {
-                                file.read_exact(&mut buffer).expect(&format!(
-                                    "Couldn't read {} bytes from {}",
-                                    x + 1,
-                                    FLAGS_FNAME
-                                ));
-                            }
-                            _ => (),
-                        }
-
-                        // Override with the required regs
-                        for i in 0..=x as usize {
-                            buffer[i] = self.regs[i];
-                        }
-
-                        // Save the flags
-                        let mut file = File::create(FLAGS_FNAME)
-                            .expect(&format!("Couldn't create {}", FLAGS_FNAME));
-                        file.write_all(&buffer)
-                            .expect(&format!("Couldn't save file {}", FLAGS_FNAME));
+                        let mut flags = self.flags.load();
+                        flags[..=x as usize].copy_from_slice(&self.regs[..=x as usize]);
+                        self.flags.save(&flags);
+                    }
+                    0xf8 if self.system == Chip8System::CHIP8X => {
+                        // tone := vx
+                        self.c8x.tone = self.regs[x as usize];
+                    }
+                    0xfb if self.system == Chip8System::CHIP8X => {
+                        // vx := port, where nothing is connected
+                        self.regs[x as usize] = 0;
                     }
                     0x85 => {
                         // loadflags vx
-                        if self.system == Chip8System::CHIP8 {
+                        if !self.system.has_schip() {
                             return;
                         }
                         let x = if self.system == Chip8System::XOCHIP {
@@ -1843,30 +2057,8 @@ impl Chip8 {
                             min(x, 7)
                         };
 
-                        match File::open(FLAGS_FNAME) {
-                            Ok(mut file) => {
-                                // If the file exist, load its contents in the required regs
-                                let mut buffer = [0; 16];
-                                file.read_exact(&mut buffer).expect(&format!(
-                                    "Couldn't read {} bytes from {}",
-                                    x + 1,
-                                    FLAGS_FNAME
-                                ));
-                                for i in 0..=x as usize {
-                                    self.regs[i] = buffer[i];
-                                }
-                            }
-                            Err(_) => {
-                                // Else init the file and clear the regs
-                                let mut file = File::create(FLAGS_FNAME)
-                                    .expect(&format!("Couldn't create {}", FLAGS_FNAME));
-                                file.write_all(&[0; 16])
-                                    .expect(&format!("Couldn't init file {}", FLAGS_FNAME));
-                                for i in 0..=x as usize {
-                                    self.regs[i] = 0;
-                                }
-                            }
-                        }
+                        let flags = self.flags.load();
+                        self.regs[..=x as usize].copy_from_slice(&flags[..=x as usize]);
                     }
                     _ => panic!("Unknown opcode ${:04x}", op),
                 }
@@ -1874,4 +2066,115 @@ impl Chip8 {
             _ => panic!("Unknown opcode ${:04x}", op),
         }
     }
+
+    /// MEGA-CHIP's opcodes from `0100` to `09ff`.
+    fn step_megachip(&mut self, op: u16) {
+        let nn = (op & 0xff) as u8;
+        let n = op & 0xf;
+
+        match op >> 8 {
+            0x1 => {
+                // ldhi i, nnnnnn
+                self.i_hi = nn;
+                self.i = (self.mem[self.pc as usize] as u16) << 8
+                    | self.mem[self.pc as usize + 1] as u16;
+                self.pc += 2;
+            }
+            0x2 => {
+                // ldpal nn
+                self.mega
+                    .load_palette(&self.mem, self.i_addr(), nn as usize);
+            }
+            0x3 => {
+                // sprw nn
+                self.mega.sprite_width = nn as usize;
+            }
+            0x4 => {
+                // sprh nn
+                self.mega.sprite_height = nn as usize;
+            }
+            0x5 => {
+                // alpha nn
+                self.mega.alpha = nn;
+            }
+            0x6 => {
+                // digisnd n
+                self.mega.play_sound(&self.mem, self.i_addr(), n == 0);
+            }
+            0x7 => {
+                // stopsnd
+                self.mega.sound = None;
+            }
+            0x8 => {
+                // bmode n
+                self.mega.blend = BlendMode::from_n(n as u8);
+            }
+            0x9 => {
+                // ccol nn
+                self.mega.collision_color = nn;
+            }
+            _ => panic!("Unknown opcode ${:04x}", op),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An instance with a 12 deep stack, running a rom that calls itself forever.
+    fn recursing() -> Chip8 {
+        let mut chip8 = Chip8::new();
+        let mut profile = Profile::new();
+        profile.stack_depth = 12;
+        chip8.set_profile(&profile);
+        chip8.load_rom(vec![0x22, 0x00]);
+        chip8
+    }
+
+    #[test]
+    fn call_wraps_past_the_stack_depth() {
+        let mut chip8 = recursing();
+        for _ in 0..13 {
+            chip8.step();
+        }
+        assert_eq!(chip8.sp, 1);
+        assert_eq!(chip8.pc, 0x200);
+    }
+
+    #[test]
+    fn compiled_call_wraps_past_the_stack_depth() {
+        let mut chip8 = recursing();
+        for _ in 0..13 {
+            chip8.run_block();
+        }
+        assert_eq!(chip8.sp, 1);
+        assert_eq!(chip8.pc, 0x200);
+    }
+
+    #[test]
+    fn return_wraps_below_the_stack() {
+        for compiled in [false, true] {
+            let mut chip8 = Chip8::new();
+            chip8.load_rom(vec![0x00, 0xee]);
+            chip8.stack[15] = 0x204;
+            if compiled {
+                chip8.run_block();
+            } else {
+                chip8.step();
+            }
+            assert_eq!(chip8.sp, 15);
+            assert_eq!(chip8.pc, 0x204);
+        }
+    }
+
+    #[test]
+    fn op_at_wraps_like_step() {
+        let mut chip8 = Chip8::new();
+        chip8.set_mem_size(0x1000);
+        chip8.mem[0xfff] = 0x12;
+        chip8.mem[0] = 0x34;
+        assert_eq!(chip8.op_at(0xfff), 0x1234);
+        assert_eq!(chip8.op_at(0x1fff), 0x1234);
+    }
 }
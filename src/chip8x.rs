@@ -0,0 +1,80 @@
+// The VP-590 colour board's colours: blue, black, green and red backgrounds
+const BACKGROUNDS: [[u8; 3]; 4] = [
+    [0x00, 0x00, 0x80],
+    [0x00, 0x00, 0x00],
+    [0x00, 0x80, 0x00],
+    [0x80, 0x00, 0x00],
+];
+const FOREGROUNDS: [[u8; 3]; 8] = [
+    [0x00, 0x00, 0x00],
+    [0xff, 0x00, 0x00],
+    [0x00, 0x00, 0xff],
+    [0xff, 0x00, 0xff],
+    [0x00, 0xff, 0x00],
+    [0xff, 0xff, 0x00],
+    [0x00, 0xff, 0xff],
+    [0xff, 0xff, 0xff],
+];
+
+// Foreground colours are set for 8x1 pixel cells of the 64x32 screen
+const ZONE_COLS: usize = 8;
+const ZONE_ROWS: usize = 32;
+
+/// CHIP-8X's colour board and tone port.
+pub struct Chip8X {
+    /// Index into the background colours, stepped by `02A0`.
+    pub background: u8,
+    /// Foreground colour of each 8x1 pixel cell.
+    pub zones: [u8; ZONE_COLS * ZONE_ROWS],
+    /// Last value sent to port 3 by `FxF8`, which sets the tone's pitch.
+    pub tone: u8,
+}
+
+impl Chip8X {
+    pub fn new() -> Self {
+        Self {
+            background: 0,
+            zones: [1; ZONE_COLS * ZONE_ROWS],
+            tone: 0,
+        }
+    }
+
+    pub fn next_background(&mut self) {
+        self.background = (self.background + 1) % BACKGROUNDS.len() as u8;
+    }
+
+    /// `Bxy0`: colour 8x4 pixel zones. `vx` holds the left zone and width minus 1
+    /// in its nibbles, `vx1` the top zone and height minus 1.
+    pub fn color_zones(&mut self, vx: u8, vx1: u8, color: u8) {
+        let (left, width) = ((vx >> 4) as usize, (vx & 0xf) as usize);
+        let (top, height) = ((vx1 >> 4) as usize, (vx1 & 0xf) as usize);
+        for zone_y in top..=top + height {
+            for row in zone_y * 4..zone_y * 4 + 4 {
+                for col in left..=left + width {
+                    self.set_zone(col, row, color);
+                }
+            }
+        }
+    }
+
+    /// `BxyN`: colour `rows` 8x1 pixel cells down from pixel `x`, `y`.
+    pub fn color_rows(&mut self, x: u8, y: u8, rows: u8, color: u8) {
+        for row in 0..rows as usize {
+            self.set_zone(x as usize / 8, y as usize + row, color);
+        }
+    }
+
+    fn set_zone(&mut self, col: usize, row: usize, color: u8) {
+        self.zones[(row % ZONE_ROWS) * ZONE_COLS + col % ZONE_COLS] = color & 7;
+    }
+
+    /// RGBA of a pixel of the 64x32 screen.
+    pub fn color(&self, lit: bool, x: usize, y: usize) -> [u8; 4] {
+        let [r, g, b] = if lit {
+            FOREGROUNDS[self.zones[(y % ZONE_ROWS) * ZONE_COLS + (x / 8) % ZONE_COLS] as usize]
+        } else {
+            BACKGROUNDS[self.background as usize]
+        };
+        [r, g, b, 0xff]
+    }
+}
@@ -0,0 +1,139 @@
+use crate::chip8::Chip8;
+
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage: leina-chip8 [options] [rom]
+
+Options:
+  --headless           Run without a window, for --frames frames
+  --frames <n>         Frames to run headless (default 60)
+  --screenshot <file>  Save a PNG of the last headless frame
+  --record <file>      Record every frame, to a GIF or raw RGBA if the name ends in .rgba
+  --scale <n>          Scale of screenshots and recordings
+  --start <addr>       Address roms are loaded and started at, eg 0x600 for ETI-660
+  --mem-size <n>       Addressable memory in bytes, eg 4096 or 0x10000
+  --assemble <file>    Assemble the Octo source rom to <file>, with a .map of source lines
+  --help               Show this message";
+
+pub struct Args {
+    pub rom: Option<PathBuf>,
+    pub headless: bool,
+    pub frames: u32,
+    pub screenshot: Option<PathBuf>,
+    pub record: Option<PathBuf>,
+    pub scale: Option<usize>,
+    pub start_address: Option<u16>,
+    pub mem_size: Option<usize>,
+    pub assemble: Option<PathBuf>,
+    pub help: bool,
+}
+
+impl Args {
+    pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut ret = Self {
+            rom: None,
+            headless: false,
+            frames: 60,
+            screenshot: None,
+            record: None,
+            scale: None,
+            start_address: None,
+            mem_size: None,
+            assemble: None,
+            help: false,
+        };
+
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("Missing value for {}", arg))
+            };
+            match arg.as_str() {
+                "--headless" => ret.headless = true,
+                "--frames" => ret.frames = parse_num(&arg, &value()?)?,
+                "--screenshot" => ret.screenshot = Some(PathBuf::from(value()?)),
+                "--record" => ret.record = Some(PathBuf::from(value()?)),
+                "--scale" => ret.scale = Some(parse_num::<usize>(&arg, &value()?)?.max(1)),
+                "--start" => {
+                    let addr = parse_addr(&arg, &value()?)?;
+                    if addr > 0xffff {
+                        return Err(format!("{} must be below 0x10000", arg));
+                    }
+                    ret.start_address = Some(addr as u16);
+                }
+                "--mem-size" => ret.mem_size = Some(parse_addr(&arg, &value()?)?),
+                "--assemble" => ret.assemble = Some(PathBuf::from(value()?)),
+                "--help" | "-h" => ret.help = true,
+                _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+                _ => ret.rom = Some(PathBuf::from(&arg)),
+            }
+        }
+
+        if ret.headless && ret.rom.is_none() {
+            return Err("--headless needs a rom".to_string());
+        }
+        if ret.assemble.is_some() && ret.rom.is_none() {
+            return Err("--assemble needs a rom".to_string());
+        }
+        Ok(ret)
+    }
+
+    /// Override the profile's start address and memory size, before loading the rom.
+    /// Fails if the rom would start past the end of memory.
+    pub fn apply(&self, chip8: &mut Chip8) -> Result<(), String> {
+        let start_address = self.start_address.unwrap_or(chip8.start_address);
+        let mem_size = self.mem_size.unwrap_or(chip8.mem_size);
+        if mem_size <= start_address as usize {
+            return Err(format!(
+                "The memory size {:#x} must be more than the start address {:#x}",
+                mem_size, start_address
+            ));
+        }
+        chip8.start_address = start_address;
+        chip8.set_mem_size(mem_size);
+        Ok(())
+    }
+}
+
+fn parse_num<T: std::str::FromStr>(arg: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value '{}' for {}", value, arg))
+}
+
+/// Parse a decimal or `0x`-prefixed hex number.
+fn parse_addr(arg: &str, value: &str) -> Result<usize, String> {
+    match value.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16)
+            .map_err(|_| format!("Invalid value '{}' for {}", value, arg)),
+        None => parse_num(arg, value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn apply_overrides_the_profile() {
+        let mut chip8 = Chip8::new();
+        let args = parse(&["--start", "0x600", "--mem-size", "4096"]).unwrap();
+        assert_eq!(args.apply(&mut chip8), Ok(()));
+        assert_eq!((chip8.start_address, chip8.mem_size), (0x600, 0x1000));
+    }
+
+    #[test]
+    fn mem_size_must_be_past_the_start() {
+        let mut chip8 = Chip8::new();
+        let args = parse(&["--mem-size", "0x200"]).unwrap();
+        assert!(args.apply(&mut chip8).is_err());
+        let args = parse(&["--start", "0x1000", "--mem-size", "0x1000"]).unwrap();
+        assert!(args.apply(&mut chip8).is_err());
+        assert_eq!(chip8.start_address, 0x200);
+    }
+}
@@ -1,3 +1,10 @@
 pub const WIDTH: usize = 128;
 pub const HEIGHT: usize = 64;
-pub const FLAGS_FNAME: &str = "flags.bin";
+pub const FLAGS_DIR_NAME: &str = "flags";
+pub const APP_DIR_NAME: &str = "leina-chip8";
+pub const SETTINGS_FNAME: &str = "settings.toml";
+pub const PROFILES_FNAME: &str = "profiles.toml";
+pub const RECENT_ROMS_FNAME: &str = "recent_roms.txt";
+pub const MAX_RECENT_ROMS: usize = 10;
+pub const MEGA_WIDTH: usize = 256;
+pub const MEGA_HEIGHT: usize = 192;
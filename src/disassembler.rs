@@ -1,19 +1,34 @@
+use crate::analysis::{Analysis, ByteKind};
+use crate::assembler::SourceMap;
+use crate::breakpoints::Breakpoints;
 use crate::chip8::{Chip8, Chip8System};
-use egui::{Color32, RichText, TextStyle, Ui};
+use egui::{
+    Color32, DragValue, Key, Label, Rect, RichText, ScrollArea, Sense, Shape, TextStyle, Ui,
+};
+use std::iter;
 
 const ADDRESS_TEXT_COLOR: Color32 = Color32::from_rgb(125, 0, 125);
 const WHITE_COLOR: Color32 = Color32::from_rgb(0xff, 0xff, 0xff);
 const FADE_COLOR: Color32 = Color32::from_rgb(0x55, 0x55, 0x55);
 const MNEM_COLOR: Color32 = Color32::from_rgb(0x00, 0x55, 0xaa);
 const REG_COLOR: Color32 = Color32::from_rgb(0xaa, 0xaa, 0x00);
+const BREAKPOINT_COLOR: Color32 = Color32::from_rgb(0xcc, 0x22, 0x22);
+const GUTTER_COLOR: Color32 = Color32::from_rgb(0x33, 0x33, 0x33);
+const LABEL_COLOR: Color32 = Color32::from_rgb(0x00, 0xaa, 0x55);
+const PC_HIGHLIGHT_COLOR: Color32 = Color32::from_rgb(0x30, 0x30, 0x50);
 const MONOSPACE: TextStyle = TextStyle::Monospace;
+/// Rows shown before the listing scrolls.
+const LISTING_ROWS: usize = 30;
 
-enum InsTokenType {
+pub enum InsTokenType {
     KeyWord(String),
+    Const24(u32),
     Const16(u16),
     Const12(u16),
     Const8(u16),
     Const4(u16),
+    /// An address that's jumped or called to, or pointed to by `i`.
+    Target(u16),
     VReg(u16),
     IReg,
     Operator(String),
@@ -22,42 +37,127 @@ enum InsTokenType {
 struct Token {
     color: Color32,
     text: String,
+    /// The address referred to, clickable in the listing.
+    target: Option<u16>,
 }
 
 pub struct Disassembler {
-    lines: Vec<Vec<Token>>,
+    /// The address typed into the Go to field.
+    goto_text: String,
+    /// Keep the current instruction in view as it moves.
+    follow_pc: bool,
+    /// An address to bring into view next frame.
+    scroll_to: Option<u16>,
+    last_pc: u16,
+    analysis: Analysis,
+    /// What `analysis` was made for, to redo it when that changes.
+    analyzed_for: Option<AnalysisKey>,
+    /// The bytes `analysis` traced as code and where they are, to redo it when the
+    /// program writes over its code. Other writes, eg to sprites, don't change it.
+    traced_code: Vec<(usize, Vec<u8>)>,
+    /// Addresses seen running, traced along with the entry point.
+    roots: Vec<u16>,
+    rows: Vec<Row>,
 }
 
-fn get_tokens(chip8: &Chip8, start_pc: u16) -> (Vec<Token>, u16) {
-    let mut pc = start_pc;
-    let mut ret = vec![];
+/// The system, start address, entry point, rom length and end of memory, which decide
+/// what the analysis finds along with the code.
+type AnalysisKey = (Chip8System, u16, u16, usize, usize);
 
-    // 1st token: the address
-    ret.push(Token {
-        color: ADDRESS_TEXT_COLOR,
-        text: format!("{:03X}", start_pc),
-    });
+fn analysis_key(chip8: &Chip8) -> AnalysisKey {
+    (
+        chip8.system,
+        chip8.start_address,
+        chip8.entry_point(),
+        chip8.rom_len,
+        Analysis::end(chip8),
+    )
+}
 
-    // 2nd set of tokens: 2 bytes used for the instruction
-    let byte = chip8.mem[pc as usize];
-    pc += 1;
-    ret.push(Token {
-        color: FADE_COLOR,
-        text: format!("{:02x}", byte),
-    });
+/// A line of the listing.
+#[derive(Clone, Copy)]
+enum Row {
+    Label(u16),
+    /// An instruction, a byte of data or, outside the rom, 2 bytes of unknown memory.
+    Line(u16),
+}
 
-    let mut op = (byte as u16) << 8;
+impl Row {
+    fn addr(&self) -> u16 {
+        match *self {
+            Row::Label(addr) | Row::Line(addr) => addr,
+        }
+    }
+}
 
-    let byte = chip8.mem[pc as usize];
-    pc += 1;
-    ret.push(Token {
-        color: FADE_COLOR,
-        text: format!("{:02x}", byte),
-    });
+/// MEGA-CHIP's extra `0nnn` opcodes, moving `pc` past the 4-byte `ldhi`'s operand.
+fn get_megachip_tokens(chip8: &Chip8, op: u16, pc: &mut u16, tokens: &mut Vec<InsTokenType>) {
+    let nn = op & 0xff;
+    let n = op & 0xf;
+
+    match op >> 8 {
+        0x0 => match nn {
+            0x10 => tokens.push(InsTokenType::KeyWord(String::from("megaoff"))),
+            0x11 => tokens.push(InsTokenType::KeyWord(String::from("megaon"))),
+            _ => {
+                tokens.push(InsTokenType::KeyWord(String::from("scroll-up")));
+                tokens.push(InsTokenType::Const4(n));
+            }
+        },
+        0x1 => {
+            let mut target = (nn as u32) << 16;
+            for shift in [8, 0] {
+                let byte = chip8.mem[*pc as usize];
+                *pc += 1;
+                target |= (byte as u32) << shift;
+            }
+            tokens.push(InsTokenType::KeyWord(String::from("ldhi")));
+            tokens.push(InsTokenType::IReg);
+            tokens.push(InsTokenType::Const24(target));
+        }
+        0x2 => {
+            tokens.push(InsTokenType::KeyWord(String::from("ldpal")));
+            tokens.push(InsTokenType::Const8(nn));
+        }
+        0x3 => {
+            tokens.push(InsTokenType::KeyWord(String::from("sprw")));
+            tokens.push(InsTokenType::Const8(nn));
+        }
+        0x4 => {
+            tokens.push(InsTokenType::KeyWord(String::from("sprh")));
+            tokens.push(InsTokenType::Const8(nn));
+        }
+        0x5 => {
+            tokens.push(InsTokenType::KeyWord(String::from("alpha")));
+            tokens.push(InsTokenType::Const8(nn));
+        }
+        0x6 => {
+            tokens.push(InsTokenType::KeyWord(String::from("digisnd")));
+            tokens.push(InsTokenType::Const4(n));
+        }
+        0x7 => {
+            tokens.push(InsTokenType::KeyWord(String::from("stopsnd")));
+        }
+        0x8 => {
+            tokens.push(InsTokenType::KeyWord(String::from("bmode")));
+            tokens.push(InsTokenType::Const4(n));
+        }
+        0x9 => {
+            tokens.push(InsTokenType::KeyWord(String::from("ccol")));
+            tokens.push(InsTokenType::Const8(nn));
+        }
+        _ => (),
+    }
+}
 
-    op |= byte as u16;
+/// Decode the instruction at `start_pc`, returning its mnemonic and operands and the
+/// address after it. `exact` spells out what the quirks make the emulator ignore, eg
+/// the `vy` of a shift, so the instruction can be reassembled as it was.
+pub fn decode(chip8: &Chip8, start_pc: u16, exact: bool) -> (Vec<InsTokenType>, u16) {
+    let mut pc = start_pc;
+    let op = ((chip8.mem[pc as usize] as u16) << 8) | (chip8.mem[pc as usize + 1] as u16);
+    pc += 2;
 
-    // 3rd set of tokens: the instruction and params
     let n0 = op >> 12;
     let x = (op >> 8) & 0xf;
     let y = (op >> 4) & 0xf;
@@ -66,12 +166,16 @@ fn get_tokens(chip8: &Chip8, start_pc: u16) -> (Vec<Token>, u16) {
     let n = op & 0xf;
 
     let mut tokens: Vec<InsTokenType> = vec![];
-    let mut is_wide = false;
 
     match n0 {
         0x0 => match nnn {
+            0x010 | 0x011 | 0x0b0..=0x0bf | 0x100..=0x9ff
+                if chip8.system == Chip8System::MEGACHIP =>
+            {
+                get_megachip_tokens(chip8, op, &mut pc, &mut tokens);
+            }
             0x0c0..=0x0cf => {
-                if chip8.system != Chip8System::CHIP8 {
+                if chip8.system.has_scroll() {
                     tokens.push(InsTokenType::KeyWord(String::from("scroll-down")));
                     tokens.push(InsTokenType::Const4(n));
                 }
@@ -82,6 +186,12 @@ fn get_tokens(chip8: &Chip8, start_pc: u16) -> (Vec<Token>, u16) {
                     tokens.push(InsTokenType::Const4(n));
                 }
             }
+            0x230 if chip8.has_hires_boot() => {
+                tokens.push(InsTokenType::KeyWord(String::from("clear")));
+            }
+            0x2a0 if chip8.system == Chip8System::CHIP8X => {
+                tokens.push(InsTokenType::KeyWord(String::from("next-background")));
+            }
             0x0e0 => {
                 tokens.push(InsTokenType::KeyWord(String::from("clear")));
             }
@@ -89,27 +199,27 @@ fn get_tokens(chip8: &Chip8, start_pc: u16) -> (Vec<Token>, u16) {
                 tokens.push(InsTokenType::KeyWord(String::from("return")));
             }
             0x0fb => {
-                if chip8.system != Chip8System::CHIP8 {
+                if chip8.system.has_scroll() {
                     tokens.push(InsTokenType::KeyWord(String::from("scroll-right")));
                 }
             }
             0x0fc => {
-                if chip8.system != Chip8System::CHIP8 {
+                if chip8.system.has_scroll() {
                     tokens.push(InsTokenType::KeyWord(String::from("scroll-left")));
                 }
             }
             0x0fd => {
-                if chip8.system != Chip8System::CHIP8 {
+                if chip8.system.has_schip() {
                     tokens.push(InsTokenType::KeyWord(String::from("exit")));
                 }
             }
             0x0fe => {
-                if chip8.system != Chip8System::CHIP8 {
+                if chip8.system.has_schip() {
                     tokens.push(InsTokenType::KeyWord(String::from("lores")));
                 }
             }
             0x0ff => {
-                if chip8.system != Chip8System::CHIP8 {
+                if chip8.system.has_schip() {
                     tokens.push(InsTokenType::KeyWord(String::from("hires")));
                 }
             }
@@ -117,11 +227,11 @@ fn get_tokens(chip8: &Chip8, start_pc: u16) -> (Vec<Token>, u16) {
         },
         0x1 => {
             tokens.push(InsTokenType::KeyWord(String::from("jump")));
-            tokens.push(InsTokenType::Const12(nnn));
+            tokens.push(InsTokenType::Target(nnn));
         }
         0x2 => {
             tokens.push(InsTokenType::KeyWord(String::from("call")));
-            tokens.push(InsTokenType::Const12(nnn));
+            tokens.push(InsTokenType::Target(nnn));
         }
         0x3 => {
             tokens.push(InsTokenType::KeyWord(String::from("if")));
@@ -145,6 +255,14 @@ fn get_tokens(chip8: &Chip8, start_pc: u16) -> (Vec<Token>, u16) {
                 tokens.push(InsTokenType::VReg(y));
                 tokens.push(InsTokenType::KeyWord(String::from("then")));
             }
+            1 => {
+                if chip8.system == Chip8System::CHIP8X {
+                    tokens.push(InsTokenType::VReg(x));
+                    tokens.push(InsTokenType::Operator(String::from("+=")));
+                    tokens.push(InsTokenType::KeyWord(String::from("nibbles")));
+                    tokens.push(InsTokenType::VReg(y));
+                }
+            }
             2 => {
                 if chip8.system == Chip8System::XOCHIP {
                     tokens.push(InsTokenType::KeyWord(String::from("save")));
@@ -207,7 +325,7 @@ fn get_tokens(chip8: &Chip8, start_pc: u16) -> (Vec<Token>, u16) {
             0x6 => {
                 tokens.push(InsTokenType::VReg(x));
                 tokens.push(InsTokenType::Operator(String::from(">>=")));
-                if chip8.quirk_shifting {
+                if chip8.quirks.shifting && !exact {
                     tokens.push(InsTokenType::Operator(String::from("1")));
                 } else {
                     tokens.push(InsTokenType::VReg(y));
@@ -221,7 +339,7 @@ fn get_tokens(chip8: &Chip8, start_pc: u16) -> (Vec<Token>, u16) {
             0xe => {
                 tokens.push(InsTokenType::VReg(x));
                 tokens.push(InsTokenType::Operator(String::from("<<=")));
-                if chip8.quirk_shifting {
+                if chip8.quirks.shifting && !exact {
                     tokens.push(InsTokenType::Operator(String::from("1")));
                 } else {
                     tokens.push(InsTokenType::VReg(y));
@@ -241,16 +359,27 @@ fn get_tokens(chip8: &Chip8, start_pc: u16) -> (Vec<Token>, u16) {
         0xa => {
             tokens.push(InsTokenType::IReg);
             tokens.push(InsTokenType::Operator(String::from(":=")));
-            tokens.push(InsTokenType::Const12(nnn));
+            tokens.push(InsTokenType::Target(nnn));
         }
         0xb => {
-            if chip8.quirk_jumping {
+            if chip8.system == Chip8System::CHIP8X {
+                if n == 0 {
+                    tokens.push(InsTokenType::KeyWord(String::from("zone-color")));
+                    tokens.push(InsTokenType::VReg(x));
+                    tokens.push(InsTokenType::VReg(y));
+                } else {
+                    tokens.push(InsTokenType::KeyWord(String::from("row-color")));
+                    tokens.push(InsTokenType::VReg(x));
+                    tokens.push(InsTokenType::VReg(y));
+                    tokens.push(InsTokenType::Const4(n));
+                }
+            } else if chip8.quirks.jumping && !exact {
                 tokens.push(InsTokenType::KeyWord(String::from("jump")));
                 tokens.push(InsTokenType::VReg(x));
                 tokens.push(InsTokenType::Const12(nnn));
             } else {
                 tokens.push(InsTokenType::KeyWord(String::from("jump0")));
-                tokens.push(InsTokenType::Const12(nnn));
+                tokens.push(InsTokenType::Target(nnn));
             }
         }
         0xc => {
@@ -276,29 +405,25 @@ fn get_tokens(chip8: &Chip8, start_pc: u16) -> (Vec<Token>, u16) {
                 tokens.push(InsTokenType::VReg(x));
                 tokens.push(InsTokenType::KeyWord(String::from("key then")));
             }
+            0xf2 if chip8.system == Chip8System::CHIP8X => {
+                tokens.push(InsTokenType::KeyWord(String::from("if")));
+                tokens.push(InsTokenType::VReg(x));
+                tokens.push(InsTokenType::KeyWord(String::from("-key2 then")));
+            }
+            0xf5 if chip8.system == Chip8System::CHIP8X => {
+                tokens.push(InsTokenType::KeyWord(String::from("if")));
+                tokens.push(InsTokenType::VReg(x));
+                tokens.push(InsTokenType::KeyWord(String::from("key2 then")));
+            }
             _ => (),
         },
         0xf => match nn {
             0x00 => {
                 if x == 0 {
                     if chip8.system == Chip8System::XOCHIP {
-                        is_wide = true;
-
-                        let byte = chip8.mem[pc as usize];
-                        pc += 1;
-                        let mut target = (byte as u16) << 8;
-                        ret.push(Token {
-                            color: FADE_COLOR,
-                            text: format!("{:02x}", byte),
-                        });
-
-                        let byte = chip8.mem[pc as usize];
-                        pc += 1;
-                        target |= byte as u16;
-                        ret.push(Token {
-                            color: FADE_COLOR,
-                            text: format!("{:02x}", byte),
-                        });
+                        let target = ((chip8.mem[pc as usize] as u16) << 8)
+                            | (chip8.mem[pc as usize + 1] as u16);
+                        pc += 2;
 
                         tokens.push(InsTokenType::IReg);
                         tokens.push(InsTokenType::Operator(String::from(":=")));
@@ -352,7 +477,7 @@ fn get_tokens(chip8: &Chip8, start_pc: u16) -> (Vec<Token>, u16) {
                 tokens.push(InsTokenType::VReg(x));
             }
             0x30 => {
-                if chip8.system != Chip8System::CHIP8 {
+                if chip8.system.has_schip() {
                     tokens.push(InsTokenType::IReg);
                     tokens.push(InsTokenType::Operator(String::from(":=")));
                     tokens.push(InsTokenType::KeyWord(String::from("bighex")));
@@ -377,40 +502,81 @@ fn get_tokens(chip8: &Chip8, start_pc: u16) -> (Vec<Token>, u16) {
                 tokens.push(InsTokenType::VReg(x));
             }
             0x75 => {
-                if chip8.system != Chip8System::CHIP8 {
+                if chip8.system.has_schip() {
                     tokens.push(InsTokenType::KeyWord(String::from("saveflags")));
                     tokens.push(InsTokenType::VReg(x));
                 }
             }
             0x85 => {
-                if chip8.system != Chip8System::CHIP8 {
+                if chip8.system.has_schip() {
                     tokens.push(InsTokenType::KeyWord(String::from("loadflags")));
                     tokens.push(InsTokenType::VReg(x));
                 }
             }
+            0xf8 if chip8.system == Chip8System::CHIP8X => {
+                tokens.push(InsTokenType::KeyWord(String::from("tone")));
+                tokens.push(InsTokenType::Operator(String::from(":=")));
+                tokens.push(InsTokenType::VReg(x));
+            }
+            0xfb if chip8.system == Chip8System::CHIP8X => {
+                tokens.push(InsTokenType::VReg(x));
+                tokens.push(InsTokenType::Operator(String::from(":=")));
+                tokens.push(InsTokenType::KeyWord(String::from("port")));
+            }
             _ => (),
         },
         _ => (),
     }
 
-    if !is_wide {
+    (tokens, pc)
+}
+
+fn get_tokens(chip8: &Chip8, start_pc: u16) -> (Vec<Token>, u16) {
+    let (tokens, pc) = decode(chip8, start_pc, false);
+    let mut ret = vec![];
+
+    // 1st token: the address
+    ret.push(Token {
+        color: ADDRESS_TEXT_COLOR,
+        text: format!("{:03X}", start_pc),
+        target: None,
+    });
+
+    // 2nd set of tokens: the 2 or 4 bytes used for the instruction
+    for addr in start_pc..pc {
+        ret.push(Token {
+            color: FADE_COLOR,
+            text: format!("{:02x}", chip8.mem[addr as usize]),
+            target: None,
+        });
+    }
+    if pc - start_pc == 2 {
         ret.push(Token {
             color: FADE_COLOR,
             text: String::from("  "),
+            target: None,
         });
         ret.push(Token {
             color: FADE_COLOR,
             text: String::from("  "),
+            target: None,
         });
     }
 
+    // 3rd set of tokens: the instruction and params
     for token in tokens {
+        let target = match token {
+            InsTokenType::Target(addr) => Some(addr),
+            _ => None,
+        };
         let (color, text) = match token {
             InsTokenType::KeyWord(kw) => (MNEM_COLOR, kw),
+            InsTokenType::Const24(val) => (WHITE_COLOR, format!("${:06x}", val)),
             InsTokenType::Const16(val) => (WHITE_COLOR, format!("${:04x}", val)),
             InsTokenType::Const12(val) => (WHITE_COLOR, format!("${:03x}", val)),
             InsTokenType::Const8(val) => (WHITE_COLOR, format!("${:02x}", val)),
             InsTokenType::Const4(val) => (WHITE_COLOR, format!("${:01x}", val)),
+            InsTokenType::Target(val) => (WHITE_COLOR, format!("${:03x}", val)),
             InsTokenType::VReg(reg) => (REG_COLOR, format!("v{:1x}", reg)),
             InsTokenType::IReg => (REG_COLOR, String::from("i")),
             InsTokenType::Operator(op) => (WHITE_COLOR, op),
@@ -418,29 +584,158 @@ fn get_tokens(chip8: &Chip8, start_pc: u16) -> (Vec<Token>, u16) {
         ret.push(Token {
             color: color,
             text: text,
+            target,
         });
     }
 
     (ret, pc)
 }
 
+/// A byte of data, drawn as the row of a sprite.
+fn get_data_tokens(chip8: &Chip8, addr: u16) -> Vec<Token> {
+    let byte = chip8.mem[addr as usize];
+    let pixels: String = (0..8)
+        .map(|bit| {
+            if byte & (0x80 >> bit) != 0 {
+                '█'
+            } else {
+                '·'
+            }
+        })
+        .collect();
+    vec![
+        Token {
+            color: ADDRESS_TEXT_COLOR,
+            text: format!("{:03X}", addr),
+            target: None,
+        },
+        Token {
+            color: FADE_COLOR,
+            text: format!("{:02x}", byte),
+            target: None,
+        },
+        Token {
+            color: WHITE_COLOR,
+            text: pixels,
+            target: None,
+        },
+    ]
+}
+
 impl Disassembler {
     pub fn new() -> Self {
-        Self { lines: vec![] }
+        Self {
+            goto_text: String::new(),
+            follow_pc: true,
+            scroll_to: None,
+            last_pc: 0,
+            analysis: Analysis::empty(),
+            analyzed_for: None,
+            traced_code: vec![],
+            roots: vec![],
+            rows: vec![],
+        }
     }
 
-    pub fn prepare(&mut self, chip8: &Chip8) {
-        self.lines = vec![];
+    /// Redo the code analysis and listing rows if the code or rom changed or `pc` is
+    /// somewhere the last analysis didn't find code.
+    fn update_analysis(&mut self, chip8: &Chip8) {
+        let key = analysis_key(chip8);
+        let pc_is_code = matches!(self.analysis.kind(chip8.pc), ByteKind::Code(_));
+        let code_unchanged = || {
+            self.traced_code
+                .iter()
+                .all(|(addr, bytes)| chip8.mem[*addr..*addr + bytes.len()] == bytes[..])
+        };
+        if pc_is_code && self.analyzed_for == Some(key) && code_unchanged() {
+            return;
+        }
 
-        let mut pc = chip8.pc;
-        for _ in 0..30 {
-            let tokens;
-            (tokens, pc) = get_tokens(chip8, pc);
-            self.lines.push(tokens);
+        if !self.roots.contains(&chip8.pc) {
+            self.roots.push(chip8.pc);
+        }
+        // The current instruction goes first, so it's decoded from where it's running
+        let roots = iter::once(chip8.pc).chain(self.roots.iter().copied());
+        self.analysis = Analysis::new(chip8, roots);
+        self.analyzed_for = Some(key);
+        self.traced_code = self
+            .analysis
+            .code_ranges()
+            .into_iter()
+            .map(|range| (range.start, chip8.mem[range].to_vec()))
+            .collect();
+
+        self.rows.clear();
+        let end = Analysis::end(chip8);
+        let mut addr = 0;
+        // Leave room to decode 4-byte instructions at the end
+        while addr + 4 <= end {
+            if self.analysis.label(addr as u16).is_some() {
+                self.rows.push(Row::Label(addr as u16));
+            }
+            self.rows.push(Row::Line(addr as u16));
+            addr += match self.analysis.kind(addr as u16) {
+                ByteKind::Code(len) => len as usize,
+                ByteKind::CodeTail | ByteKind::Data => 1,
+                ByteKind::Unknown if self.analysis.kind(addr as u16 + 1) == ByteKind::Unknown => 2,
+                ByteKind::Unknown => 1,
+            };
+        }
+    }
+
+    fn display_megachip(&self, ui: &mut Ui, chip8: &Chip8) {
+        let mega = &chip8.mega;
+        let sound = match mega.sound {
+            Some(sound) => format!(
+                "{:06x} {}Hz {} bytes{}",
+                sound.addr,
+                sound.rate,
+                sound.len,
+                if sound.looping { " looping" } else { "" }
+            ),
+            None => String::from("-"),
+        };
+        let rows = [
+            [
+                ("Mega:", format!("{}", mega.enabled)),
+                (
+                    "Sprite:",
+                    format!("{}x{}", mega.sprite_width, mega.sprite_height),
+                ),
+                ("Alpha:", format!("{:02x}", mega.alpha)),
+            ],
+            [
+                ("Blend:", format!("{:?}", mega.blend)),
+                ("Collision:", format!("{:02x}", mega.collision_color)),
+                ("Sample:", sound),
+            ],
+        ];
+        for row in rows {
+            ui.horizontal(|ui| {
+                for (name, value) in row {
+                    ui.label(
+                        RichText::new(name)
+                            .color(MNEM_COLOR)
+                            .text_style(MONOSPACE.clone()),
+                    );
+                    ui.label(
+                        RichText::new(value)
+                            .color(WHITE_COLOR)
+                            .text_style(MONOSPACE.clone()),
+                    );
+                }
+            });
         }
     }
 
-    pub fn display(&self, ui: &mut Ui, chip8: &Chip8) {
+    /// Returns the address picked with "Run to cursor", if any.
+    pub fn display(
+        &mut self,
+        ui: &mut Ui,
+        chip8: &mut Chip8,
+        breakpoints: &mut Breakpoints,
+        source_map: &SourceMap,
+    ) -> Option<u16> {
         ui.horizontal(|ui| {
             ui.label(
                 RichText::new("PC:")
@@ -452,12 +747,31 @@ impl Disassembler {
                     .color(WHITE_COLOR)
                     .text_style(MONOSPACE.clone()),
             );
+            // The source line, for roms assembled from Octo source
+            if let Some(line) = source_map.line(chip8.pc) {
+                ui.label(
+                    RichText::new("Line:")
+                        .color(MNEM_COLOR)
+                        .text_style(MONOSPACE.clone()),
+                );
+                ui.label(
+                    RichText::new(format!("{}", line))
+                        .color(WHITE_COLOR)
+                        .text_style(MONOSPACE.clone()),
+                );
+            }
             ui.label(
                 RichText::new("I:")
                     .color(MNEM_COLOR)
                     .text_style(MONOSPACE.clone()),
             );
-            if chip8.system == Chip8System::XOCHIP {
+            if chip8.system == Chip8System::MEGACHIP {
+                ui.label(
+                    RichText::new(format!("{:06x}", chip8.i_addr()))
+                        .color(WHITE_COLOR)
+                        .text_style(MONOSPACE.clone()),
+                );
+            } else if chip8.system == Chip8System::XOCHIP {
                 ui.label(
                     RichText::new(format!("{:04x}", chip8.i))
                         .color(WHITE_COLOR)
@@ -548,7 +862,40 @@ impl Disassembler {
                 );
             }
         });
-        // todo: save file bytes
+        if chip8.system == Chip8System::MEGACHIP {
+            self.display_megachip(ui, chip8);
+        }
+        ui.separator();
+
+        // RPL user flags, as saved by saveflags
+        let mut flags = chip8.flags.load();
+        let mut flags_changed = false;
+        ui.horizontal(|ui| {
+            ui.label(
+                RichText::new("Flags:")
+                    .color(MNEM_COLOR)
+                    .text_style(MONOSPACE.clone()),
+            );
+            if ui.button("Clear flags").clicked() {
+                flags = [0; 16];
+                flags_changed = true;
+            }
+        });
+        for flag_row in 0..2 {
+            ui.horizontal(|ui| {
+                let flag_start = flag_row * 8;
+                for flag in flags[flag_start..flag_start + 8].iter_mut() {
+                    let response =
+                        ui.add(DragValue::new(flag).hexadecimal(2, false, false).speed(0.1));
+                    if response.changed() {
+                        flags_changed = true;
+                    }
+                }
+            });
+        }
+        if flags_changed {
+            chip8.flags.save(&flags);
+        }
         ui.separator();
 
         for reg_row in 0..4 {
@@ -609,17 +956,132 @@ impl Disassembler {
         });
         ui.separator();
 
-        for i in 0..self.lines.len() {
-            let line = &self.lines[i];
-            ui.horizontal(|ui| {
-                for token in line {
-                    ui.label(
-                        RichText::new(token.text.clone())
-                            .color(token.color)
-                            .text_style(MONOSPACE.clone()),
-                    );
+        ui.horizontal(|ui| {
+            let goto_label = ui.label("Go to:");
+            let response = ui
+                .text_edit_singleline(&mut self.goto_text)
+                .labelled_by(goto_label.id);
+            self.goto_text.retain(|c| c.is_ascii_hexdigit());
+            let entered = response.lost_focus() && ui.input(|i| i.key_pressed(Key::Enter));
+            if ui.button("Go").clicked() || entered {
+                if let Ok(addr) = u16::from_str_radix(&self.goto_text, 16) {
+                    self.scroll_to = Some(addr);
+                    self.follow_pc = false;
                 }
-            });
+            }
+            if ui.checkbox(&mut self.follow_pc, "Follow PC").changed() && self.follow_pc {
+                self.scroll_to = Some(chip8.pc);
+            }
+            if ui
+                .button("Reanalyze")
+                .on_hover_text("Forget the addresses seen running and trace from the entry point")
+                .clicked()
+            {
+                self.roots.clear();
+                self.analyzed_for = None;
+            }
+        });
+        if self.follow_pc && chip8.pc != self.last_pc {
+            self.scroll_to = Some(chip8.pc);
+        }
+        self.last_pc = chip8.pc;
+
+        self.update_analysis(chip8);
+        let row_height = ui.text_style_height(&MONOSPACE);
+        let spaced_height = row_height + ui.spacing().item_spacing.y;
+
+        let mut scroll = ScrollArea::vertical()
+            .max_height(spaced_height * LISTING_ROWS as f32)
+            .auto_shrink([false, true]);
+        if let Some(addr) = self.scroll_to.take() {
+            // Put the address in the middle
+            let row = self.rows.partition_point(|row| row.addr() < addr);
+            let offset = (row as f32 - (LISTING_ROWS / 2) as f32) * spaced_height;
+            scroll = scroll.vertical_scroll_offset(offset.max(0.0));
         }
+
+        let mut run_to = None;
+        scroll.show_rows(ui, row_height, self.rows.len(), |ui, row_range| {
+            for row in row_range {
+                let addr = match self.rows[row] {
+                    Row::Label(addr) => {
+                        let label = self.analysis.label(addr).unwrap_or_default();
+                        ui.label(
+                            RichText::new(format!(": {}", label))
+                                .color(LABEL_COLOR)
+                                .text_style(MONOSPACE.clone()),
+                        );
+                        continue;
+                    }
+                    Row::Line(addr) => addr,
+                };
+                let line = match self.analysis.kind(addr) {
+                    ByteKind::Data => get_data_tokens(chip8, addr),
+                    _ => get_tokens(chip8, addr).0,
+                };
+                let background = ui.painter().add(Shape::Noop);
+                let response = ui
+                    .horizontal(|ui| {
+                        let gutter_color = if breakpoints.covers(addr) {
+                            BREAKPOINT_COLOR
+                        } else {
+                            GUTTER_COLOR
+                        };
+                        let gutter = RichText::new("●")
+                            .color(gutter_color)
+                            .text_style(MONOSPACE.clone());
+                        if ui
+                            .add(Label::new(gutter).sense(Sense::click()))
+                            .on_hover_text("Toggle breakpoint")
+                            .clicked()
+                        {
+                            breakpoints.toggle(addr);
+                        }
+
+                        for token in &line {
+                            let text = RichText::new(token.text.clone())
+                                .color(token.color)
+                                .text_style(MONOSPACE.clone());
+                            match token.target {
+                                Some(target) => {
+                                    let text = match self.analysis.label(target) {
+                                        Some(label) => RichText::new(label)
+                                            .color(token.color)
+                                            .text_style(MONOSPACE.clone()),
+                                        None => text,
+                                    };
+                                    if ui
+                                        .add(Label::new(text.underline()).sense(Sense::click()))
+                                        .on_hover_text("Go to")
+                                        .clicked()
+                                    {
+                                        self.scroll_to = Some(target);
+                                        self.follow_pc = false;
+                                    }
+                                }
+                                None => {
+                                    ui.label(text);
+                                }
+                            }
+                        }
+                    })
+                    .response;
+                if addr == chip8.pc {
+                    let rect =
+                        Rect::from_x_y_ranges(ui.max_rect().x_range(), response.rect.y_range());
+                    ui.painter().set(
+                        background,
+                        Shape::rect_filled(rect, 0.0, PC_HIGHLIGHT_COLOR),
+                    );
+                }
+                response.interact(Sense::click()).context_menu(|ui| {
+                    if ui.button("Run to cursor").clicked() {
+                        run_to = Some(addr);
+                        ui.close_menu();
+                    }
+                });
+            }
+        });
+        run_to
     }
 }
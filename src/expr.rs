@@ -0,0 +1,352 @@
+use crate::chip8::Chip8;
+
+/// A parsed condition, eg `v3 == 0x10 && [I] != 0`, evaluated against the machine state.
+#[derive(Clone)]
+pub enum Expr {
+    Num(i64),
+    VReg(usize),
+    I,
+    Pc,
+    Delay,
+    Sound,
+    Sp,
+    /// A byte of memory at the address.
+    Mem(Box<Expr>),
+    Not(Box<Expr>),
+    Neg(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Add,
+    Sub,
+    BitAnd,
+    BitOr,
+}
+
+#[derive(Clone, PartialEq)]
+enum Token {
+    Num(i64),
+    Ident(String),
+    Op(&'static str),
+}
+
+// Longest first, so `<=` isn't read as `<` then `=`
+const OPERATORS: [&str; 18] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<", ">", "+", "-", "&", "|", "!", "(", ")", "[", "]", "=",
+];
+
+fn at(message: String, column: usize) -> String {
+    format!("{} at column {}", message, column)
+}
+
+/// The tokens and the column each starts at, counting from 1.
+fn tokenize(text: &str) -> Result<Vec<(Token, usize)>, String> {
+    let mut ret = vec![];
+    let mut rest = text.trim_start();
+    while !rest.is_empty() {
+        let column = text.len() - rest.len() + 1;
+        let c = rest.chars().next().unwrap();
+        if c.is_ascii_alphanumeric() || c == '_' {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            let word = &rest[..len];
+            if c.is_ascii_digit() {
+                let num = parse_num(word).map_err(|err| at(err, column))?;
+                ret.push((Token::Num(num), column));
+            } else {
+                ret.push((Token::Ident(word.to_ascii_lowercase()), column));
+            }
+            rest = &rest[len..];
+        } else {
+            let op = OPERATORS
+                .iter()
+                .find(|op| rest.starts_with(*op))
+                .ok_or_else(|| at(format!("Unexpected '{}'", c), column))?;
+            if *op == "=" {
+                return Err(at(String::from("Use '==' to compare"), column));
+            }
+            ret.push((Token::Op(op), column));
+            rest = &rest[op.len()..];
+        }
+        rest = rest.trim_start();
+    }
+    Ok(ret)
+}
+
+fn parse_num(word: &str) -> Result<i64, String> {
+    let lower = word.to_ascii_lowercase();
+    let result = if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16)
+    } else if let Some(bin) = lower.strip_prefix("0b") {
+        i64::from_str_radix(bin, 2)
+    } else {
+        lower.parse()
+    };
+    result.map_err(|_| format!("Invalid number '{}'", word))
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    /// The column just past the text, for errors at its end.
+    end: usize,
+}
+
+impl Parser {
+    fn peek_op(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some((Token::Op(op), _)) => Some(*op),
+            _ => None,
+        }
+    }
+
+    /// The column of the next token.
+    fn column(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .map_or(self.end, |(_, column)| *column)
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        if self.peek_op() == Some(op) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(at(format!("Expected '{}'", op), self.column()))
+        }
+    }
+
+    /// Parse binary operators of the precedence `level` and above, 0 being the loosest.
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        const LEVELS: [&[(&str, BinOp)]; 4] = [
+            &[("||", BinOp::Or)],
+            &[("&&", BinOp::And)],
+            &[
+                ("==", BinOp::Eq),
+                ("!=", BinOp::Ne),
+                ("<=", BinOp::Le),
+                (">=", BinOp::Ge),
+                ("<", BinOp::Lt),
+                (">", BinOp::Gt),
+            ],
+            &[
+                ("+", BinOp::Add),
+                ("-", BinOp::Sub),
+                ("&", BinOp::BitAnd),
+                ("|", BinOp::BitOr),
+            ],
+        ];
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(&(_, op)) = LEVELS[level]
+            .iter()
+            .find(|(text, _)| self.peek_op() == Some(*text))
+        {
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.peek_op() {
+            Some("!") => {
+                self.pos += 1;
+                Ok(Expr::Not(Box::new(self.unary()?)))
+            }
+            Some("-") => {
+                self.pos += 1;
+                Ok(Expr::Neg(Box::new(self.unary()?)))
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        let (token, column) = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| at(String::from("Unexpected end of condition"), self.end))?;
+        self.pos += 1;
+        match token {
+            Token::Num(num) => Ok(Expr::Num(num)),
+            Token::Ident(name) => match name.as_str() {
+                "i" => Ok(Expr::I),
+                "pc" => Ok(Expr::Pc),
+                "dt" => Ok(Expr::Delay),
+                "st" => Ok(Expr::Sound),
+                "sp" => Ok(Expr::Sp),
+                _ => {
+                    let reg = name
+                        .strip_prefix('v')
+                        .filter(|hex| hex.len() == 1)
+                        .and_then(|hex| usize::from_str_radix(hex, 16).ok());
+                    match reg {
+                        Some(reg) => Ok(Expr::VReg(reg)),
+                        None => Err(at(format!("Unknown name '{}'", name), column)),
+                    }
+                }
+            },
+            Token::Op("(") => {
+                let expr = self.binary(0)?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Token::Op("[") => {
+                let expr = self.binary(0)?;
+                self.expect("]")?;
+                Ok(Expr::Mem(Box::new(expr)))
+            }
+            Token::Op(op) => Err(at(format!("Unexpected '{}'", op), column)),
+        }
+    }
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, String> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            pos: 0,
+            end: text.len() + 1,
+        };
+        let expr = parser.binary(0)?;
+        if parser.pos != parser.tokens.len() {
+            let message = String::from("Unexpected text after the condition");
+            return Err(at(message, parser.column()));
+        }
+        Ok(expr)
+    }
+
+    pub fn eval(&self, chip8: &Chip8) -> i64 {
+        match self {
+            Expr::Num(num) => *num,
+            Expr::VReg(reg) => chip8.regs[*reg] as i64,
+            Expr::I => chip8.i_addr() as i64,
+            Expr::Pc => chip8.pc as i64,
+            Expr::Delay => chip8.delay as i64,
+            Expr::Sound => chip8.sound as i64,
+            Expr::Sp => chip8.sp as i64,
+            Expr::Mem(addr) => {
+                let addr = addr.eval(chip8);
+                usize::try_from(addr)
+                    .ok()
+                    .and_then(|addr| chip8.mem.get(addr))
+                    .map_or(0, |byte| *byte as i64)
+            }
+            Expr::Not(expr) => (expr.eval(chip8) == 0) as i64,
+            Expr::Neg(expr) => expr.eval(chip8).wrapping_neg(),
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(chip8);
+                // Short-circuit like the C-style operators they look like
+                match op {
+                    BinOp::Or if lhs != 0 => return 1,
+                    BinOp::And if lhs == 0 => return 0,
+                    _ => (),
+                }
+                let rhs = rhs.eval(chip8);
+                match op {
+                    BinOp::Or | BinOp::And => (rhs != 0) as i64,
+                    BinOp::Eq => (lhs == rhs) as i64,
+                    BinOp::Ne => (lhs != rhs) as i64,
+                    BinOp::Lt => (lhs < rhs) as i64,
+                    BinOp::Le => (lhs <= rhs) as i64,
+                    BinOp::Gt => (lhs > rhs) as i64,
+                    BinOp::Ge => (lhs >= rhs) as i64,
+                    BinOp::Add => lhs.wrapping_add(rhs),
+                    BinOp::Sub => lhs.wrapping_sub(rhs),
+                    BinOp::BitAnd => lhs & rhs,
+                    BinOp::BitOr => lhs | rhs,
+                }
+            }
+        }
+    }
+
+    /// True if the condition holds, ie it evaluates to non-zero.
+    pub fn test(&self, chip8: &Chip8) -> bool {
+        self.eval(chip8) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(text: &str) -> i64 {
+        Expr::parse(text).unwrap().eval(&Chip8::new())
+    }
+
+    fn error(text: &str) -> String {
+        match Expr::parse(text) {
+            Ok(_) => panic!("'{}' parsed", text),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 == 3"), 1);
+        assert_eq!(eval("2 | 1 == 1"), 0);
+        assert_eq!(eval("1 || 0 && 0"), 1);
+        assert_eq!(eval("0 && 1 || 1"), 1);
+        assert_eq!(eval("5 - 2 - 1"), 2);
+        assert_eq!(eval("!0 + 1"), 2);
+        assert_eq!(eval("-(1 - 3)"), 2);
+        assert_eq!(eval("(1 || 0) + 1"), 2);
+    }
+
+    #[test]
+    fn number_literals() {
+        assert_eq!(eval("0x1f"), 31);
+        assert_eq!(eval("0X1F"), 31);
+        assert_eq!(eval("0b101"), 5);
+        assert_eq!(eval("10"), 10);
+    }
+
+    #[test]
+    fn machine_state() {
+        let mut chip8 = Chip8::new();
+        chip8.regs[3] = 0x10;
+        chip8.regs[0xf] = 1;
+        chip8.i = 0x300;
+        chip8.mem[0x301] = 7;
+        let test = |text: &str| Expr::parse(text).unwrap().test(&chip8);
+        assert!(test("v3 == 0x10 && VF"));
+        assert!(test("[i + 1] == 7"));
+        assert!(test("[I] == 0"));
+        assert!(test("pc == 0x200"));
+        assert!(!test("v3 < 0x10"));
+        // Out of memory reads as 0
+        assert!(test("[-1] == 0"));
+    }
+
+    #[test]
+    fn errors_have_columns() {
+        assert_eq!(error("v3 = 1"), "Use '==' to compare at column 4");
+        assert_eq!(error("v3 == @"), "Unexpected '@' at column 7");
+        assert_eq!(error("0xzz"), "Invalid number '0xzz' at column 1");
+        assert_eq!(error("(v3 == 1"), "Expected ')' at column 9");
+        assert_eq!(error("1 + vz"), "Unknown name 'vz' at column 5");
+        assert_eq!(
+            error("1 2"),
+            "Unexpected text after the condition at column 3"
+        );
+        assert_eq!(error("v3 =="), "Unexpected end of condition at column 6");
+        assert_eq!(error(") == 1"), "Unexpected ')' at column 1");
+    }
+}
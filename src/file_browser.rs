@@ -0,0 +1,159 @@
+use egui::{Context, ScrollArea};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+struct Entry {
+    name: String,
+    path: PathBuf,
+    is_dir: bool,
+}
+
+pub struct FileBrowser {
+    pub open: bool,
+    dir: PathBuf,
+    dir_text: String,
+    entries: Vec<Entry>,
+    selected: Option<usize>,
+    error: Option<String>,
+}
+
+impl FileBrowser {
+    pub fn new() -> Self {
+        let dir = env::current_dir().unwrap_or(PathBuf::from("."));
+        let mut ret = Self {
+            open: false,
+            dir_text: dir.to_string_lossy().to_string(),
+            dir,
+            entries: vec![],
+            selected: None,
+            error: None,
+        };
+        ret.refresh();
+        ret
+    }
+
+    /// Show the browser in `dir`, e.g. the folder of the current ROM.
+    pub fn open_at(&mut self, dir: Option<PathBuf>) {
+        if let Some(dir) = dir {
+            if dir.is_dir() {
+                self.set_dir(dir);
+            }
+        }
+        self.open = true;
+        self.refresh();
+    }
+
+    fn set_dir(&mut self, dir: PathBuf) {
+        self.dir_text = dir.to_string_lossy().to_string();
+        self.dir = dir;
+        self.selected = None;
+    }
+
+    fn refresh(&mut self) {
+        self.entries = vec![];
+        self.error = None;
+        match fs::read_dir(&self.dir) {
+            Ok(read_dir) => {
+                for entry in read_dir.flatten() {
+                    let name = entry.file_name().to_string_lossy().to_string();
+                    if name.starts_with('.') {
+                        continue;
+                    }
+                    self.entries.push(Entry {
+                        name,
+                        path: entry.path(),
+                        is_dir: entry.path().is_dir(),
+                    });
+                }
+            }
+            Err(err) => {
+                self.error = Some(format!("{}", err));
+            }
+        }
+
+        // Directories first, then files, both alphabetically
+        self.entries.sort_by(|a, b| {
+            b.is_dir
+                .cmp(&a.is_dir)
+                .then_with(|| a.name.to_lowercase().cmp(&b.name.to_lowercase()))
+        });
+    }
+
+    /// Draw the browser window, returning the file the user picked, if any.
+    pub fn show(&mut self, ctx: &Context) -> Option<PathBuf> {
+        let mut picked = None;
+        let mut new_dir = None;
+        let mut open = self.open;
+
+        egui::Window::new("Open ROM")
+            .open(&mut open)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    if ui.button("Up").clicked() {
+                        if let Some(parent) = self.dir.parent() {
+                            new_dir = Some(parent.to_path_buf());
+                        }
+                    }
+                    let response = ui.text_edit_singleline(&mut self.dir_text);
+                    if response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                        new_dir = Some(PathBuf::from(&self.dir_text));
+                    }
+                });
+                ui.separator();
+
+                if let Some(error) = &self.error {
+                    ui.label(error.as_str());
+                }
+
+                ScrollArea::vertical().max_height(300.0).show(ui, |ui| {
+                    for (i, entry) in self.entries.iter().enumerate() {
+                        let text = if entry.is_dir {
+                            format!("{}/", entry.name)
+                        } else {
+                            entry.name.clone()
+                        };
+                        let response = ui.selectable_label(self.selected == Some(i), text);
+                        if response.clicked() {
+                            self.selected = Some(i);
+                        }
+                        if response.double_clicked() {
+                            if entry.is_dir {
+                                new_dir = Some(entry.path.clone());
+                            } else {
+                                picked = Some(entry.path.clone());
+                            }
+                        }
+                    }
+                });
+                ui.separator();
+
+                ui.horizontal(|ui| {
+                    let selected_file = match self.selected {
+                        Some(i) if !self.entries[i].is_dir => Some(self.entries[i].path.clone()),
+                        _ => None,
+                    };
+                    if ui
+                        .add_enabled(selected_file.is_some(), egui::Button::new("Open"))
+                        .clicked()
+                    {
+                        picked = selected_file;
+                    }
+                    if ui.button("Cancel").clicked() {
+                        self.open = false;
+                    }
+                });
+            });
+
+        if let Some(dir) = new_dir {
+            self.set_dir(dir);
+            self.refresh();
+        }
+
+        self.open &= open;
+        if picked.is_some() {
+            self.open = false;
+        }
+        picked
+    }
+}
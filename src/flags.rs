@@ -0,0 +1,169 @@
+use crate::constants::{APP_DIR_NAME, FLAGS_DIR_NAME};
+
+use log::warn;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+/// Backing store for the RPL user flags saved and loaded by `Fx75`/`Fx85`.
+pub trait FlagsStorage {
+    fn load(&mut self) -> [u8; 16];
+    fn save(&mut self, flags: &[u8; 16]);
+}
+
+/// Flags that only live as long as the emulator, eg for headless runs or without a ROM.
+pub struct MemoryFlags {
+    flags: [u8; 16],
+}
+
+impl MemoryFlags {
+    pub fn new() -> Self {
+        Self { flags: [0; 16] }
+    }
+}
+
+impl FlagsStorage for MemoryFlags {
+    fn load(&mut self) -> [u8; 16] {
+        self.flags
+    }
+
+    fn save(&mut self, flags: &[u8; 16]) {
+        self.flags = *flags;
+    }
+}
+
+/// Flags persisted to a file per ROM in the user's data dir.
+pub struct FileFlags {
+    path: PathBuf,
+    cache: Option<[u8; 16]>,
+}
+
+impl FileFlags {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path, cache: None }
+    }
+
+    /// Flags for the ROM with the given hash, eg `~/.local/share/leina-chip8/flags/<hash>.bin`.
+    pub fn for_rom(rom_hash: &str) -> Self {
+        let dir = match dirs::data_dir() {
+            Some(dir) => dir.join(APP_DIR_NAME).join(FLAGS_DIR_NAME),
+            None => PathBuf::from(FLAGS_DIR_NAME),
+        };
+        Self::new(dir.join(format!("{}.bin", rom_hash)))
+    }
+
+    fn read(&self) -> [u8; 16] {
+        let mut flags = [0; 16];
+        match fs::read(&self.path) {
+            Ok(contents) => {
+                if contents.len() != flags.len() {
+                    warn!(
+                        "{} has {} bytes instead of {}, using what's there",
+                        self.path.to_string_lossy(),
+                        contents.len(),
+                        flags.len()
+                    );
+                }
+                let len = contents.len().min(flags.len());
+                flags[..len].copy_from_slice(&contents[..len]);
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => (),
+            Err(err) => {
+                warn!("Couldn't read {}: {}", self.path.to_string_lossy(), err);
+            }
+        }
+        flags
+    }
+}
+
+impl FlagsStorage for FileFlags {
+    fn load(&mut self) -> [u8; 16] {
+        match self.cache {
+            Some(flags) => flags,
+            None => {
+                let flags = self.read();
+                self.cache = Some(flags);
+                flags
+            }
+        }
+    }
+
+    fn save(&mut self, flags: &[u8; 16]) {
+        self.cache = Some(*flags);
+        if let Some(dir) = self.path.parent() {
+            if let Err(err) = fs::create_dir_all(dir) {
+                warn!("Couldn't create {}: {}", dir.to_string_lossy(), err);
+                return;
+            }
+        }
+        if let Err(err) = fs::write(&self.path, flags) {
+            warn!("Couldn't save {}: {}", self.path.to_string_lossy(), err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    /// A path in the temp dir, removed first so each test starts without the file.
+    fn temp_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("leina-chip8-{}-{}", process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn memory_flags_keep_what_was_saved() {
+        let mut flags = MemoryFlags::new();
+        assert_eq!(flags.load(), [0; 16]);
+        let saved = [7; 16];
+        flags.save(&saved);
+        assert_eq!(flags.load(), saved);
+    }
+
+    #[test]
+    fn missing_file_loads_zeros() {
+        let mut flags = FileFlags::new(temp_path("missing.bin"));
+        assert_eq!(flags.load(), [0; 16]);
+    }
+
+    #[test]
+    fn saved_flags_load_from_the_file() {
+        let path = temp_path("saved.bin");
+        let saved: [u8; 16] = core::array::from_fn(|i| i as u8);
+        FileFlags::new(path.clone()).save(&saved);
+        assert_eq!(FileFlags::new(path.clone()).load(), saved);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn short_file_loads_what_is_there() {
+        let path = temp_path("short.bin");
+        fs::write(&path, [1, 2, 3]).unwrap();
+        let mut expected = [0; 16];
+        expected[..3].copy_from_slice(&[1, 2, 3]);
+        assert_eq!(FileFlags::new(path.clone()).load(), expected);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn long_file_loads_the_first_16_bytes() {
+        let path = temp_path("long.bin");
+        fs::write(&path, [0xff; 20]).unwrap();
+        assert_eq!(FileFlags::new(path.clone()).load(), [0xff; 16]);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn unreadable_file_loads_zeros() {
+        // A directory can't be read as a file
+        let path = temp_path("dir.bin");
+        let _ = fs::remove_dir(&path);
+        fs::create_dir(&path).unwrap();
+        assert_eq!(FileFlags::new(path.clone()).load(), [0; 16]);
+        let _ = fs::remove_dir(&path);
+    }
+}
@@ -1,14 +1,22 @@
 use crate::breakpoints::Breakpoints;
+use crate::capture::{timestamped_path, RecordFormat};
 use crate::chip8::{Chip8, Chip8System};
 use crate::constants::{HEIGHT, WIDTH};
 use crate::disassembler::Disassembler;
+use crate::file_browser::FileBrowser;
+use crate::fonts::FontSet;
+use crate::palette::Palette;
+use crate::recent_roms::RecentRoms;
+use crate::renderer::{FrameFilter, ScreenPlacement, ScreenRenderer};
+use crate::runner::RunUntil;
 use crate::watchpoints::Watchpoints;
 use crate::System;
 
-use egui::{ClippedPrimitive, Context, TexturesDelta};
+use egui::{ClippedPrimitive, Context, TextureHandle, TextureOptions, TexturesDelta};
 use egui_memory_editor::MemoryEditor;
 use egui_wgpu::renderer::{Renderer, ScreenDescriptor};
 use pixels::{wgpu, PixelsContext};
+use serde::{Deserialize, Serialize};
 use winit::event_loop::EventLoopWindowTarget;
 use winit::window::Window;
 
@@ -27,14 +35,49 @@ pub(crate) struct Framework {
 }
 
 struct Gui {
-    breakpoints_open: bool,
-    controls_open: bool,
-    disassembler_open: bool,
-    frame_time_open: bool,
-    mem_editor_open: bool,
-    quirks_open: bool,
-    vram_editor_open: bool,
-    watchpoints_open: bool,
+    open: OpenWindows,
+    file_browser: FileBrowser,
+    palette_text: String,
+    palette_error: Option<String>,
+    capture_error: Option<String>,
+    screen_texture: Option<TextureHandle>,
+    /// Instructions run by the Controls window's Run button.
+    run_count: u32,
+}
+
+/// Which tool windows are shown, persisted in the settings file.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct OpenWindows {
+    breakpoints: bool,
+    capture: bool,
+    controls: bool,
+    disassembler: bool,
+    display: bool,
+    frame_time: bool,
+    mem_editor: bool,
+    palette: bool,
+    quirks: bool,
+    vram_editor: bool,
+    watchpoints: bool,
+}
+
+impl Default for OpenWindows {
+    fn default() -> Self {
+        Self {
+            breakpoints: false,
+            capture: false,
+            controls: true,
+            disassembler: false,
+            display: false,
+            frame_time: true,
+            mem_editor: false,
+            palette: false,
+            quirks: false,
+            vram_editor: false,
+            watchpoints: false,
+        }
+    }
 }
 
 impl Framework {
@@ -88,16 +131,30 @@ impl Framework {
         self.screen_descriptor.pixels_per_point = scale_factor as f32;
     }
 
+    /// The currently shown tool windows.
+    pub(crate) fn open_windows(&self) -> OpenWindows {
+        self.gui.open.clone()
+    }
+
+    /// Restore previously shown tool windows.
+    pub(crate) fn set_open_windows(&mut self, open: OpenWindows) {
+        self.gui.open = open;
+    }
+
     /// Prepare egui.
     pub(crate) fn prepare(
         &mut self,
         window: &Window,
         chip8: &mut Chip8,
-        disassembler: &Disassembler,
+        disassembler: &mut Disassembler,
         breakpoints: &mut Breakpoints,
         mem_editor: &mut MemoryEditor,
         vram_editor: &mut MemoryEditor,
         watchpoints: &mut Watchpoints,
+        recent_roms: &mut RecentRoms,
+        screen_renderer: &mut ScreenRenderer,
+        screen_frame: &[u8],
+        screen_size: (usize, usize),
         system: &mut System,
     ) {
         // Run the egui frame and create all paint jobs to prepare for rendering.
@@ -112,6 +169,10 @@ impl Framework {
                 mem_editor,
                 vram_editor,
                 watchpoints,
+                recent_roms,
+                screen_renderer,
+                screen_frame,
+                screen_size,
                 system,
             );
         });
@@ -150,7 +211,8 @@ impl Framework {
                     view: render_target,
                     resolve_target: None,
                     ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Load,
+                        // The game screen is drawn by egui too, so nothing is under it
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
                         store: true,
                     },
                 })],
@@ -173,14 +235,88 @@ impl Gui {
     /// Create a `Gui`.
     fn new() -> Self {
         Self {
-            breakpoints_open: false,
-            controls_open: true,
-            disassembler_open: false,
-            frame_time_open: true,
-            mem_editor_open: false,
-            quirks_open: false,
-            vram_editor_open: false,
-            watchpoints_open: false,
+            open: OpenWindows::default(),
+            file_browser: FileBrowser::new(),
+            palette_text: String::new(),
+            palette_error: None,
+            capture_error: None,
+            screen_texture: None,
+            run_count: 100,
+        }
+    }
+
+    /// Draw the game screen, either behind the windows or in a panel.
+    fn screen(
+        &mut self,
+        ctx: &Context,
+        frame: &[u8],
+        size: (usize, usize),
+        screen_renderer: &ScreenRenderer,
+    ) {
+        let image = egui::ColorImage::from_rgba_unmultiplied([size.0, size.1], frame);
+        let texture_id = match &mut self.screen_texture {
+            Some(texture) => {
+                texture.set(image, TextureOptions::NEAREST);
+                texture.id()
+            }
+            None => {
+                let texture = ctx.load_texture("screen", image, TextureOptions::NEAREST);
+                self.screen_texture.insert(texture).id()
+            }
+        };
+
+        let [r, g, b] = screen_renderer.border;
+        let border = egui::Color32::from_rgb(r, g, b);
+        let pixels_per_point = ctx.pixels_per_point();
+        let paint = |painter: &egui::Painter, rect: egui::Rect| {
+            painter.rect_filled(rect, 0.0, border);
+
+            // Fit in physical pixels, and align to them so integer scaling stays sharp
+            let avail = (
+                rect.width() * pixels_per_point,
+                rect.height() * pixels_per_point,
+            );
+            let (width, height) = screen_renderer.fit(size, avail);
+            let center = rect.center();
+            let min = egui::pos2(
+                (center.x * pixels_per_point - width / 2.0).round() / pixels_per_point,
+                (center.y * pixels_per_point - height / 2.0).round() / pixels_per_point,
+            );
+            let screen_size = egui::vec2(width, height) / pixels_per_point;
+            let screen_rect = egui::Rect::from_min_size(min, screen_size);
+            let uv = egui::Rect::from_min_max(egui::pos2(0.0, 0.0), egui::pos2(1.0, 1.0));
+            painter.image(texture_id, screen_rect, uv, egui::Color32::WHITE);
+        };
+        let show = |ui: &mut egui::Ui| {
+            let (rect, _) = ui.allocate_exact_size(ui.available_size(), egui::Sense::hover());
+            paint(ui.painter(), rect);
+        };
+
+        let frame = egui::Frame::none().fill(border);
+        match screen_renderer.placement {
+            ScreenPlacement::Background => {
+                let painter = ctx.layer_painter(egui::LayerId::background());
+                paint(&painter, ctx.available_rect());
+            }
+            ScreenPlacement::LeftPanel => {
+                egui::SidePanel::left("screen_panel")
+                    .default_width(512.0)
+                    .frame(frame)
+                    .show(ctx, show);
+            }
+            ScreenPlacement::RightPanel => {
+                egui::SidePanel::right("screen_panel")
+                    .default_width(512.0)
+                    .frame(frame)
+                    .show(ctx, show);
+            }
+            ScreenPlacement::BottomPanel => {
+                egui::TopBottomPanel::bottom("screen_panel")
+                    .resizable(true)
+                    .default_height(256.0)
+                    .frame(frame)
+                    .show(ctx, show);
+            }
         }
     }
 
@@ -189,67 +325,152 @@ impl Gui {
         &mut self,
         ctx: &Context,
         chip8: &mut Chip8,
-        disassembler: &Disassembler,
+        disassembler: &mut Disassembler,
         breakpoints: &mut Breakpoints,
         mem_editor: &mut MemoryEditor,
         vram_editor: &mut MemoryEditor,
         watchpoints: &mut Watchpoints,
+        recent_roms: &mut RecentRoms,
+        screen_renderer: &mut ScreenRenderer,
+        screen_frame: &[u8],
+        screen_size: (usize, usize),
         system: &mut System,
     ) {
         egui::TopBottomPanel::top("menubar_container").show(ctx, |ui| {
             egui::menu::bar(ui, |ui| {
+                ui.menu_button("File", |ui| {
+                    if ui.button("Open ROM...").clicked() {
+                        let dir = system
+                            .rom_path
+                            .as_ref()
+                            .and_then(|path| path.parent())
+                            .map(|dir| dir.to_path_buf());
+                        self.file_browser.open_at(dir);
+                        ui.close_menu();
+                    };
+
+                    ui.menu_button("Open Recent", |ui| {
+                        if recent_roms.paths.is_empty() {
+                            ui.label("No recent ROMs");
+                        }
+                        for path in &recent_roms.paths {
+                            if ui.button(path.to_string_lossy()).clicked() {
+                                system.open_rom = Some(path.clone());
+                                ui.close_menu();
+                            }
+                        }
+                        ui.separator();
+                        if ui.button("Clear Recent").clicked() {
+                            recent_roms.clear();
+                            ui.close_menu();
+                        }
+                    });
+
+                    let is_source = system
+                        .rom_path
+                        .as_ref()
+                        .is_some_and(|path| path.extension().is_some_and(|ext| ext == "8o"));
+                    if ui
+                        .add_enabled(is_source, egui::Button::new("Assemble and Run"))
+                        .clicked()
+                    {
+                        system.assemble_pressed = true;
+                        ui.close_menu();
+                    }
+                    if ui.button("Export Octo Source").clicked() {
+                        system.export_octo_pressed = true;
+                        ui.close_menu();
+                    }
+
+                    ui.separator();
+                    ui.checkbox(&mut system.hot_reload, "Reload ROM on change");
+                    ui.add_enabled(
+                        system.hot_reload,
+                        egui::Checkbox::new(&mut system.hot_reload_keep_quirks, "Keep quirks"),
+                    );
+                    ui.add_enabled(
+                        system.hot_reload,
+                        egui::Checkbox::new(
+                            &mut system.hot_reload_keep_debug,
+                            "Keep breakpoints and watchpoints",
+                        ),
+                    );
+                });
+
                 ui.menu_button("Tools", |ui| {
                     if ui.button("Breakpoints").clicked() {
-                        self.breakpoints_open = true;
+                        self.open.breakpoints = true;
+                        ui.close_menu();
+                    };
+
+                    if ui.button("Capture").clicked() {
+                        self.open.capture = true;
                         ui.close_menu();
                     };
 
                     if ui.button("Controls").clicked() {
-                        self.controls_open = true;
+                        self.open.controls = true;
                         ui.close_menu();
                     };
 
                     if ui.button("Disassembly").clicked() {
-                        self.disassembler_open = true;
+                        self.open.disassembler = true;
+                        ui.close_menu();
+                    };
+
+                    if ui.button("Display").clicked() {
+                        self.open.display = true;
                         ui.close_menu();
                     };
 
                     if ui.button("Speed").clicked() {
-                        self.frame_time_open = true;
+                        self.open.frame_time = true;
                         ui.close_menu();
                     };
 
                     if ui.button("Memory Viewer").clicked() {
-                        self.mem_editor_open = true;
+                        self.open.mem_editor = true;
+                        ui.close_menu();
+                    };
+
+                    if ui.button("Palette").clicked() {
+                        self.open.palette = true;
                         ui.close_menu();
                     };
 
                     if ui.button("Quirks").clicked() {
-                        self.quirks_open = true;
+                        self.open.quirks = true;
                         ui.close_menu();
                     };
 
                     if ui.button("VRAM Viewer").clicked() {
-                        self.vram_editor_open = true;
+                        self.open.vram_editor = true;
                         ui.close_menu();
                     };
 
                     if ui.button("Watchpoints").clicked() {
-                        self.watchpoints_open = true;
+                        self.open.watchpoints = true;
                         ui.close_menu();
                     };
                 })
             });
         });
 
+        // Added before any windows, so they're kept out of a screen panel
+        self.screen(ctx, screen_frame, screen_size, screen_renderer);
+
+        if let Some(path) = self.file_browser.show(ctx) {
+            system.open_rom = Some(path);
+        }
+
         egui::Window::new("Breakpoints")
-            .open(&mut self.breakpoints_open)
+            .open(&mut self.open.breakpoints)
             .show(ctx, |ui| {
                 breakpoints.display(ui);
             });
 
         egui::Window::new("Controls")
-            .open(&mut self.controls_open)
+            .open(&mut self.open.controls)
             .show(ctx, |ui| {
                 ui.horizontal(|ui| {
                     if ui.button("Reset").clicked() {
@@ -265,64 +486,269 @@ impl Gui {
                     if ui.button("Step").clicked() {
                         system.step_pressed = true;
                     }
+                    if ui.button("Step over").clicked() {
+                        match RunUntil::step_over(chip8) {
+                            Some(until) => {
+                                system.run_until = Some(until);
+                                chip8.paused = false;
+                            }
+                            None => system.step_pressed = true,
+                        }
+                    }
+                    ui.add_enabled_ui(chip8.sp != 0, |ui| {
+                        if ui.button("Step out").clicked() {
+                            system.run_until = RunUntil::step_out(chip8);
+                            chip8.paused = false;
+                        }
+                    });
                 });
                 ui.horizontal(|ui| {
-                    if ui.button("CHIP-8").clicked() {
-                        chip8.set_system(Chip8System::CHIP8);
+                    if ui.button("Run").clicked() {
+                        system.run_until = Some(RunUntil::Count(self.run_count));
+                        chip8.paused = false;
                     }
-                    if ui.button("Legacy SCHIP").clicked() {
-                        chip8.set_system(Chip8System::LSCHIP);
-                    }
-                    if ui.button("Modern SCHIP").clicked() {
-                        chip8.set_system(Chip8System::MSCHIP);
-                    }
-                    if ui.button("XO-CHIP").clicked() {
-                        chip8.set_system(Chip8System::XOCHIP);
+                    ui.add(egui::DragValue::new(&mut self.run_count).clamp_range(1..=u32::MAX));
+                    ui.label("instructions");
+                });
+                ui.horizontal_wrapped(|ui| {
+                    for profile in &system.profiles {
+                        let selected = profile.name == chip8.profile_name;
+                        if ui
+                            .selectable_label(selected, profile.name.as_str())
+                            .clicked()
+                        {
+                            // These load and boot roms differently, so restart the rom
+                            if profile.start_address != chip8.start_address
+                                || profile.system == Chip8System::CHIP8HIRES
+                            {
+                                system.reset_pressed = true;
+                            }
+                            chip8.set_profile(profile);
+                        }
                     }
                 });
             });
 
         egui::Window::new("Disassembly")
-            .open(&mut self.disassembler_open)
+            .open(&mut self.open.disassembler)
+            .show(ctx, |ui| {
+                let source_map = &system.source_map;
+                if let Some(addr) = disassembler.display(ui, chip8, breakpoints, source_map) {
+                    system.run_until = Some(RunUntil::Cursor(addr));
+                    chip8.paused = false;
+                }
+            });
+
+        egui::Window::new("Display")
+            .open(&mut self.open.display)
             .show(ctx, |ui| {
-                disassembler.display(ui, &chip8);
+                ui.checkbox(&mut screen_renderer.native_lores, "Show lores at 64x32");
+                ui.checkbox(&mut screen_renderer.integer_scaling, "Integer scaling");
+                ui.checkbox(&mut screen_renderer.keep_aspect, "Keep aspect ratio");
+                ui.horizontal(|ui| {
+                    ui.color_edit_button_srgb(&mut screen_renderer.border);
+                    ui.label("Border");
+                });
+                egui::ComboBox::from_label("Placement")
+                    .selected_text(match screen_renderer.placement {
+                        ScreenPlacement::Background => "Background",
+                        ScreenPlacement::LeftPanel => "Left panel",
+                        ScreenPlacement::RightPanel => "Right panel",
+                        ScreenPlacement::BottomPanel => "Bottom panel",
+                    })
+                    .show_ui(ui, |ui| {
+                        let placement = &mut screen_renderer.placement;
+                        ui.selectable_value(placement, ScreenPlacement::Background, "Background");
+                        ui.selectable_value(placement, ScreenPlacement::LeftPanel, "Left panel");
+                        ui.selectable_value(placement, ScreenPlacement::RightPanel, "Right panel");
+                        ui.selectable_value(
+                            placement,
+                            ScreenPlacement::BottomPanel,
+                            "Bottom panel",
+                        );
+                    });
+                ui.separator();
+
+                ui.label("Flicker reduction:");
+                ui.radio_value(&mut screen_renderer.filter, FrameFilter::None, "None");
+                ui.radio_value(
+                    &mut screen_renderer.filter,
+                    FrameFilter::Phosphor,
+                    "Phosphor",
+                );
+                ui.add_enabled(
+                    screen_renderer.filter == FrameFilter::Phosphor,
+                    egui::Slider::new(&mut screen_renderer.decay, 0.0..=0.95).text("Decay"),
+                );
+                ui.radio_value(
+                    &mut screen_renderer.filter,
+                    FrameFilter::OrLastTwo,
+                    "OR last two frames",
+                );
+            });
+
+        egui::Window::new("Capture")
+            .open(&mut self.open.capture)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Scale:");
+                    ui.add(egui::DragValue::new(&mut system.capture_scale).clamp_range(1..=16));
+                });
+                if ui.button("Screenshot (F12)").clicked() {
+                    system.screenshot_pressed = true;
+                }
+                ui.separator();
+
+                ui.horizontal(|ui| {
+                    ui.label("Record as:");
+                    ui.radio_value(&mut system.record_format, RecordFormat::Gif, "GIF");
+                    ui.radio_value(&mut system.record_format, RecordFormat::Raw, "Raw RGBA");
+                });
+                if system.recorder.is_recording() {
+                    ui.label(format!(
+                        "Recording to {}",
+                        system.recorder.path().to_string_lossy()
+                    ));
+                    if ui.button("Stop recording").clicked() {
+                        system.recorder.stop();
+                    }
+                } else if ui.button("Start recording").clicked() {
+                    let path = timestamped_path("recording", system.record_format.extension());
+                    let result = system.recorder.start(
+                        &path,
+                        system.record_format,
+                        chip8.max_screen_size(),
+                        system.capture_scale,
+                    );
+                    if let Err(err) = result {
+                        self.capture_error = Some(err);
+                    } else {
+                        self.capture_error = None;
+                    }
+                }
+                if let Some(error) = &self.capture_error {
+                    ui.label(error.as_str());
+                }
             });
 
+        let mem_size = chip8.mem_size.min(chip8.mem.len());
+        mem_editor.set_address_range("CPU", 0..mem_size);
         mem_editor.window_ui(
             ctx,
-            &mut self.mem_editor_open,
+            &mut self.open.mem_editor,
             &mut chip8.mem,
             |mem, address| mem.get(address).copied(),
             |mem, address, val| {
-                if address < 0x1000 {
+                if address < mem_size {
                     mem[address] = val;
                 }
             },
         );
 
+        egui::Window::new("Palette")
+            .open(&mut self.open.palette)
+            .show(ctx, |ui| {
+                egui::ComboBox::from_label("Preset")
+                    .selected_text(system.palette.name.as_str())
+                    .show_ui(ui, |ui| {
+                        for preset in Palette::presets() {
+                            let selected = preset.name == system.palette.name;
+                            if ui
+                                .selectable_label(selected, preset.name.as_str())
+                                .clicked()
+                            {
+                                system.palette = preset;
+                            }
+                        }
+                    });
+                ui.separator();
+
+                let mut edited = false;
+                let labels = ["Background", "Plane 1", "Plane 2", "Both planes"];
+                egui::Grid::new("palette_colors_4").show(ui, |ui| {
+                    for (color, label) in system.palette.colors_4.iter_mut().zip(labels) {
+                        ui.label(label);
+                        edited |= ui.color_edit_button_srgb(color).changed();
+                        ui.end_row();
+                    }
+                });
+                ui.collapsing("16 colors", |ui| {
+                    egui::Grid::new("palette_colors_16").show(ui, |ui| {
+                        for (i, color) in system.palette.colors_16.iter_mut().enumerate() {
+                            ui.label(format!("{:x}", i));
+                            edited |= ui.color_edit_button_srgb(color).changed();
+                            if i % 4 == 3 {
+                                ui.end_row();
+                            }
+                        }
+                    });
+                });
+                if edited {
+                    system.palette.name = String::from("Custom");
+                }
+                ui.separator();
+
+                ui.label("Octo palette (4 or 16 colors):");
+                ui.text_edit_singleline(&mut self.palette_text);
+                ui.horizontal(|ui| {
+                    if ui.button("Load").clicked() {
+                        match Palette::from_octo_str("Custom", &self.palette_text) {
+                            Ok(palette) => {
+                                system.palette = palette;
+                                self.palette_error = None;
+                            }
+                            Err(err) => {
+                                self.palette_error = Some(err);
+                            }
+                        }
+                    }
+                    if ui.button("From current").clicked() {
+                        self.palette_text = system.palette.to_octo_str();
+                    }
+                });
+                if let Some(err) = &self.palette_error {
+                    ui.label(err.as_str());
+                }
+            });
+
         egui::Window::new("Quirks")
-            .open(&mut self.quirks_open)
+            .open(&mut self.open.quirks)
             .show(ctx, |ui| {
-                ui.checkbox(&mut chip8.quirk_vf_reset, "vF reset");
-                ui.checkbox(&mut chip8.quirk_memory, "Memory");
-                ui.checkbox(&mut chip8.quirk_disp_wait, "Display wait");
-                ui.checkbox(&mut chip8.quirk_clipping, "Clipping");
-                ui.checkbox(&mut chip8.quirk_shifting, "Shifting");
-                ui.checkbox(&mut chip8.quirk_jumping, "Jumping");
+                ui.checkbox(&mut chip8.quirks.vf_reset, "vF reset");
+                ui.checkbox(&mut chip8.quirks.memory, "Memory");
+                ui.checkbox(&mut chip8.quirks.disp_wait, "Display wait");
+                ui.checkbox(&mut chip8.quirks.clipping, "Clipping");
+                ui.checkbox(&mut chip8.quirks.shifting, "Shifting");
+                ui.checkbox(&mut chip8.quirks.jumping, "Jumping");
                 ui.checkbox(
-                    &mut chip8.quirk_disp_wait_lores,
+                    &mut chip8.quirks.disp_wait_lores,
                     "Display wait (lores-only)",
                 );
                 ui.checkbox(
-                    &mut chip8.quirk_scroll_full_lores,
+                    &mut chip8.quirks.scroll_full_lores,
                     "Scroll full pixels in lores",
                 );
-                ui.checkbox(&mut chip8.quirk_16_colors, "16 colors");
+                ui.checkbox(&mut chip8.quirks.colors_16, "16 colors");
+                ui.separator();
+
+                egui::ComboBox::from_label("Font")
+                    .selected_text(chip8.font.name())
+                    .show_ui(ui, |ui| {
+                        for font in FontSet::ALL {
+                            if ui
+                                .selectable_label(chip8.font == font, font.name())
+                                .clicked()
+                            {
+                                chip8.font = font;
+                                chip8.font.load(&mut chip8.mem);
+                            }
+                        }
+                    });
             });
 
         vram_editor.window_ui(
             ctx,
-            &mut self.vram_editor_open,
+            &mut self.open.vram_editor,
             &mut chip8.vram,
             |mem, address| mem.get(address).copied(),
             |mem, address, val| {
@@ -333,13 +759,13 @@ impl Gui {
         );
 
         egui::Window::new("Watchpoints")
-            .open(&mut self.watchpoints_open)
+            .open(&mut self.open.watchpoints)
             .show(ctx, |ui| {
                 watchpoints.display(ui);
             });
 
         egui::Window::new("Speed")
-            .open(&mut self.frame_time_open)
+            .open(&mut self.open.frame_time)
             .show(ctx, |ui| {
                 ui.label(format!(
                     "Frame time: {:?}",
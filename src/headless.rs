@@ -0,0 +1,76 @@
+use crate::assembler;
+use crate::breakpoints::Breakpoints;
+use crate::capture::{save_screenshot, RecordFormat, Recorder};
+use crate::chip8::Chip8;
+use crate::cli::Args;
+use crate::flags::MemoryFlags;
+use crate::renderer::{Screen, ScreenRenderer};
+use crate::runner::run_frame;
+use crate::settings::{rom_hash, Settings};
+use crate::watchpoints::Watchpoints;
+use crate::System;
+
+use log::{info, warn};
+
+/// Run a rom without a window, using the saved settings, eg to record it or
+/// take a screenshot from a script.
+pub fn run(args: &Args) -> Result<(), String> {
+    let rom_path = args.rom.as_ref().ok_or("No rom given")?;
+    let rom_file = assembler::read_rom(rom_path)?;
+
+    let settings = Settings::load();
+    let mut breakpoints = Breakpoints::new();
+    let mut chip8 = Chip8::new();
+    let mut system = System::new();
+    let mut watchpoints = Watchpoints::new();
+    system.rom_hash = Some(rom_hash(&rom_file));
+    settings.emulation_for(&system.rom_hash).apply(
+        &mut chip8,
+        &mut system,
+        &mut breakpoints,
+        &mut watchpoints,
+    );
+    // Scripted runs mustn't change the flags saved for interactive sessions
+    chip8.flags = Box::new(MemoryFlags::new());
+    args.apply(&mut chip8)?;
+    // Source is assembled for where the profile loads the rom
+    let rom = assembler::build(rom_path, &rom_file, chip8.start_address)?.rom;
+    chip8.load_rom(rom);
+    chip8.paused = false;
+
+    let scale = args.scale.unwrap_or(1);
+    let mut recorder = Recorder::new();
+    if let Some(path) = &args.record {
+        let format = RecordFormat::from_path(path);
+        recorder.start(path, format, chip8.max_screen_size(), scale)?;
+    }
+
+    let mut ticks_left = system.ins_per_frame;
+    let mut frames = 0;
+    while frames < args.frames {
+        if run_frame(
+            &mut chip8,
+            &mut breakpoints,
+            &mut watchpoints,
+            &mut None,
+            system.ins_per_frame,
+            &mut ticks_left,
+        ) {
+            frames += 1;
+            recorder.capture(&Screen::new(&chip8), &system.palette);
+        }
+        if chip8.paused {
+            warn!("Stopped at {:03X} after {} frames", chip8.pc, frames);
+            break;
+        }
+    }
+    recorder.stop();
+
+    if let Some(path) = &args.screenshot {
+        let screen_renderer = ScreenRenderer::new();
+        let screen = Screen::new(&chip8);
+        save_screenshot(path, &screen, &system.palette, &screen_renderer, scale)?;
+        info!("Saved {}", path.to_string_lossy());
+    }
+    Ok(())
+}
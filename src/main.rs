@@ -1,47 +1,89 @@
+use crate::assembler::SourceMap;
 use crate::breakpoints::Breakpoints;
+use crate::capture::{save_screenshot, timestamped_path, RecordFormat, Recorder};
 use crate::chip8::Chip8;
+use crate::cli::{Args, USAGE};
 use crate::constants::{HEIGHT, WIDTH};
 use crate::disassembler::Disassembler;
+use crate::flags::{FileFlags, FlagsStorage, MemoryFlags};
 use crate::gui::Framework;
 use crate::keyboard::Keyboard;
+use crate::palette::Palette;
+use crate::profiles::Profile;
+use crate::recent_roms::RecentRoms;
+use crate::renderer::{Screen, ScreenRenderer};
+use crate::rom_watcher::RomWatcher;
+use crate::runner::{run_frame, RunUntil};
+use crate::settings::{rom_hash, EmulationSettings, Settings};
 use crate::watchpoints::Watchpoints;
 
 use egui_memory_editor::MemoryEditor;
 use error_iter::ErrorIter as _;
-use log::error;
+use log::{error, info};
 use pixels::{Error, Pixels, SurfaceTexture};
 use std::env;
-use std::fs::{metadata, File};
-use std::io::Read;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process;
 use std::time::Instant;
 use winit::{
     dpi::LogicalSize,
-    event::{Event, VirtualKeyCode},
+    event::{Event, VirtualKeyCode, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
-    window::WindowBuilder,
+    window::{Window, WindowBuilder},
 };
 use winit_input_helper::WinitInputHelper;
 
+mod analysis;
+mod assembler;
 mod breakpoints;
+mod capture;
 mod chip8;
+mod chip8x;
+mod cli;
 mod constants;
 mod disassembler;
+mod expr;
+mod file_browser;
+mod flags;
+mod fonts;
 mod gui;
+mod headless;
 mod keyboard;
+mod megachip;
+mod octo_export;
+mod palette;
+mod profiles;
+mod recent_roms;
+mod renderer;
+mod rom_watcher;
+mod runner;
+mod settings;
 mod watchpoints;
 
-fn get_file_as_byte_vec(filename: &String) -> Vec<u8> {
-    let mut f = File::open(&filename).expect("no file found");
-    let metadata = metadata(&filename).expect("unable to read metadata");
-    let mut buffer = vec![0; metadata.len() as usize];
-    f.read(&mut buffer).expect("buffer overflow");
-
-    buffer
-}
-
 struct System {
     pub reset_pressed: bool,
     pub step_pressed: bool,
+    /// Step over, step out, run to cursor or run N instructions in progress.
+    pub run_until: Option<RunUntil>,
+    pub open_rom: Option<PathBuf>,
+    pub rom_path: Option<PathBuf>,
+    pub rom_hash: Option<String>,
+    /// Where the rom's instructions are in its source, if it was assembled.
+    pub source_map: SourceMap,
+    pub hot_reload: bool,
+    pub hot_reload_keep_quirks: bool,
+    pub hot_reload_keep_debug: bool,
+    pub palette: Palette,
+    /// The presets and user profiles, listed in the Controls window.
+    pub profiles: Vec<Profile>,
+    pub screenshot_pressed: bool,
+    pub export_octo_pressed: bool,
+    pub assemble_pressed: bool,
+    pub capture_scale: usize,
+    pub record_format: RecordFormat,
+    pub recorder: Recorder,
     pub captured_instant: Instant,
     pub ins_per_frame: i32,
 }
@@ -51,19 +93,91 @@ impl System {
         Self {
             reset_pressed: false,
             step_pressed: false,
+            run_until: None,
+            open_rom: None,
+            rom_path: None,
+            rom_hash: None,
+            source_map: SourceMap::default(),
+            hot_reload: true,
+            hot_reload_keep_quirks: true,
+            hot_reload_keep_debug: true,
+            palette: Palette::new(),
+            profiles: Profile::load_all(),
+            screenshot_pressed: false,
+            export_octo_pressed: false,
+            assemble_pressed: false,
+            capture_scale: 4,
+            record_format: RecordFormat::Gif,
+            recorder: Recorder::new(),
             captured_instant: Instant::now(),
             ins_per_frame: 200000,
         }
     }
 }
 
+fn set_title(window: &Window, rom_path: &Option<PathBuf>) {
+    match rom_path.as_ref().and_then(|path| path.file_name()) {
+        Some(name) => window.set_title(&format!("CHIP-8 - {}", name.to_string_lossy())),
+        None => window.set_title("CHIP-8"),
+    }
+}
+
+/// Assemble the source rom for `--assemble`, for where running it would load it.
+fn assemble_rom(source: &Path, out: &Path, args: &Args) -> Result<(), String> {
+    let contents = assembler::read_rom(source)?;
+    let settings = Settings::load();
+    let mut chip8 = Chip8::new();
+    let hash = Some(rom_hash(&contents));
+    chip8.set_profile(&settings.emulation_for(&hash).profile);
+    args.apply(&mut chip8)?;
+    assembler::assemble_to(source, &contents, out, chip8.start_address)
+}
+
+/// RPL flags are kept per ROM, or just in memory while no ROM is loaded.
+fn flags_storage(rom_hash: &Option<String>) -> Box<dyn FlagsStorage> {
+    match rom_hash {
+        Some(hash) => Box::new(FileFlags::for_rom(hash)),
+        None => Box::new(MemoryFlags::new()),
+    }
+}
+
 fn main() -> Result<(), Error> {
     env_logger::init();
+    let args = match Args::parse(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            process::exit(2);
+        }
+    };
+    if args.help {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    if args.headless {
+        if let Err(err) = headless::run(&args) {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+        return Ok(());
+    }
+    if let (Some(rom_path), Some(out)) = (&args.rom, &args.assemble) {
+        if let Err(err) = assemble_rom(rom_path, out, &args) {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
+        return Ok(());
+    }
+
     let event_loop = EventLoop::new();
     let mut input = WinitInputHelper::new();
+    let mut settings = Settings::load();
     let window = {
         let size = LogicalSize::new(WIDTH as f64, HEIGHT as f64);
-        let scaled_size = LogicalSize::new(WIDTH as f64 * 10.0, HEIGHT as f64 * 20.0);
+        let scaled_size = LogicalSize::new(
+            settings.window_width.unwrap_or(WIDTH as f64 * 10.0),
+            settings.window_height.unwrap_or(HEIGHT as f64 * 20.0),
+        );
         WindowBuilder::new()
             .with_title("CHIP-8")
             .with_inner_size(scaled_size)
@@ -72,14 +186,6 @@ fn main() -> Result<(), Error> {
             .unwrap()
     };
 
-    // Init chip-8 with a rom
-    let args: Vec<String> = env::args().collect();
-    let rom_path = args.get(1).unwrap();
-
-    let mut chip8 = Chip8::new();
-    let rom = get_file_as_byte_vec(rom_path);
-    chip8.load_rom(rom.clone());
-
     // Init some gui-related objects
     let mut breakpoints = Breakpoints::new();
     let mut disassembler = Disassembler::new();
@@ -87,6 +193,9 @@ fn main() -> Result<(), Error> {
     let mut mem_editor = MemoryEditor::new()
         .with_address_range("CPU", 0..0x1000)
         .with_window_title("Memory Viewer");
+    let mut recent_roms = RecentRoms::load();
+    let mut screen_renderer = ScreenRenderer::new();
+    let mut rom_watcher = RomWatcher::new();
     let mut system = System::new();
     let mut vram_editor = MemoryEditor::new()
         .with_address_range("VRAM", 0..WIDTH * HEIGHT)
@@ -97,6 +206,70 @@ fn main() -> Result<(), Error> {
     vram_editor.options.show_ascii = false;
     let mut watchpoints = Watchpoints::new();
 
+    // Restore the settings from the last session
+    let mut chip8 = Chip8::new();
+    system.hot_reload = settings.hot_reload;
+    system.hot_reload_keep_quirks = settings.hot_reload_keep_quirks;
+    system.hot_reload_keep_debug = settings.hot_reload_keep_debug;
+
+    // Init chip-8 with a rom, if one was passed
+    let mut rom_file = vec![];
+    if let Some(rom_path) = args.rom.clone() {
+        rom_file = match assembler::read_rom(&rom_path) {
+            Ok(contents) => contents,
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        };
+        recent_roms.add(&rom_path);
+        rom_watcher.watch(&rom_path);
+        system.rom_path = Some(rom_path);
+        system.rom_hash = Some(rom_hash(&rom_file));
+        chip8.flags = flags_storage(&system.rom_hash);
+    }
+    settings.emulation_for(&system.rom_hash).apply(
+        &mut chip8,
+        &mut system,
+        &mut breakpoints,
+        &mut watchpoints,
+    );
+    // After the profile is known, as it decides where the rom goes
+    if let Err(err) = args.apply(&mut chip8) {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+    let mut rom = vec![];
+    if let Some(rom_path) = &system.rom_path {
+        match assembler::build(rom_path, &rom_file, chip8.start_address) {
+            Ok(program) => {
+                rom = program.rom;
+                system.source_map = program.source_map;
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                process::exit(1);
+            }
+        }
+    }
+    chip8.load_rom(rom.clone());
+    set_title(&window, &system.rom_path);
+    if let Some(scale) = args.scale {
+        system.capture_scale = scale;
+    }
+    if let Some(path) = &args.record {
+        system.record_format = RecordFormat::from_path(path);
+        let result = system.recorder.start(
+            path,
+            system.record_format,
+            chip8.max_screen_size(),
+            system.capture_scale,
+        );
+        if let Err(err) = result {
+            error!("Couldn't record to {}: {}", path.to_string_lossy(), err);
+        }
+    }
+
     // Init pixels and egui
     let (mut pixels, mut framework) = {
         let window_size = window.inner_size();
@@ -113,22 +286,113 @@ fn main() -> Result<(), Error> {
 
         (pixels, framework)
     };
+    framework.set_open_windows(settings.windows.clone());
 
     let mut ticks_left = system.ins_per_frame;
+    let mut screen_frame = vec![];
 
     event_loop.run(move |event, _, control_flow| {
         if input.update(&event) {
+            if let Some(path) = system.open_rom.take() {
+                // Source is assembled for where the rom's own settings load it
+                let opened = assembler::read_rom(&path).and_then(|contents| {
+                    let hash = Some(rom_hash(&contents));
+                    let start = settings.emulation_for(&hash).profile.start_address;
+                    Ok((assembler::build(&path, &contents, start)?, hash))
+                });
+                match opened {
+                    Ok((program, hash)) => {
+                        // Remember the settings used with the previous rom
+                        settings.store_emulation(
+                            EmulationSettings::capture(&chip8, &system, &breakpoints, &watchpoints),
+                            &system.rom_hash,
+                        );
+
+                        rom = program.rom;
+                        system.source_map = program.source_map;
+                        recent_roms.add(&path);
+                        rom_watcher.watch(&path);
+                        system.rom_path = Some(path);
+                        system.rom_hash = hash;
+                        settings.emulation_for(&system.rom_hash).apply(
+                            &mut chip8,
+                            &mut system,
+                            &mut breakpoints,
+                            &mut watchpoints,
+                        );
+                        system.reset_pressed = true;
+                        set_title(&window, &system.rom_path);
+                    }
+                    Err(err) => error!("{}", err),
+                }
+            }
+
+            // Reload the rom if it was changed on disk, or reassemble it when asked
+            let mut reloaded = false;
+            let assemble = mem::take(&mut system.assemble_pressed);
+            if (rom_watcher.changed() && system.hot_reload) || assemble {
+                if let Some(path) = &system.rom_path {
+                    // Source is assembled for where the reset below loads it
+                    let start = if system.hot_reload_keep_quirks {
+                        chip8.start_address
+                    } else {
+                        Chip8::new().start_address
+                    };
+                    let reread = assembler::read_rom(path).and_then(|contents| {
+                        let program = assembler::build(path, &contents, start)?;
+                        Ok((program, rom_hash(&contents)))
+                    });
+                    match reread {
+                        Ok((program, hash)) => {
+                            rom = program.rom;
+                            system.source_map = program.source_map;
+                            reloaded = true;
+                            system.rom_hash = Some(hash);
+                            system.reset_pressed = true;
+                        }
+                        Err(err) => error!("{}", err),
+                    }
+                }
+            }
+
             if system.reset_pressed {
                 system.reset_pressed = false;
                 system.step_pressed = false;
-                chip8 = Chip8::new();
+                system.run_until = None;
+                let prev_chip8 = mem::replace(&mut chip8, Chip8::new());
+                chip8.flags = flags_storage(&system.rom_hash);
+                if !reloaded || system.hot_reload_keep_quirks {
+                    chip8.copy_quirks(&prev_chip8);
+                }
                 chip8.load_rom(rom.clone());
+
+                if reloaded {
+                    chip8.paused = prev_chip8.paused && !assemble;
+                    if !system.hot_reload_keep_debug {
+                        breakpoints.clear();
+                        watchpoints.clear();
+                    }
+                }
             }
 
             system.captured_instant = Instant::now();
 
             // Close events
             if input.key_pressed(VirtualKeyCode::Escape) || input.close_requested() {
+                let size = window.inner_size().to_logical::<f64>(window.scale_factor());
+                settings.window_width = Some(size.width);
+                settings.window_height = Some(size.height);
+                settings.windows = framework.open_windows();
+                settings.hot_reload = system.hot_reload;
+                settings.hot_reload_keep_quirks = system.hot_reload_keep_quirks;
+                settings.hot_reload_keep_debug = system.hot_reload_keep_debug;
+                settings.store_emulation(
+                    EmulationSettings::capture(&chip8, &system, &breakpoints, &watchpoints),
+                    &system.rom_hash,
+                );
+                settings.save();
+                system.recorder.stop();
+
                 *control_flow = ControlFlow::Exit;
                 return;
             }
@@ -158,56 +422,54 @@ fn main() -> Result<(), Error> {
                 system.step_pressed = false;
             }
 
-            if chip8.paused {
-                ticks_left = 0;
-            } else {
-                while ticks_left > 0 {
-                    if !watchpoints.watchpoints.is_empty() {
-                        let accesses = chip8.check_mem_access();
-                        if watchpoints.check_mem_access(accesses) {
-                            chip8.paused = true;
-                            ticks_left = 0;
-                            break;
-                        }
-                    }
-
-                    // No JIT
-                    // chip8.step();
-                    // ticks_left -= 1;
-
-                    // JIT
-                    let cyc = chip8.run_block();
-                    ticks_left -= cyc;
-
-                    if chip8.halted {
-                        ticks_left = 0;
-                        break;
-                    }
-
-                    if breakpoints.check(chip8.pc) && !chip8.halted {
-                        chip8.paused = true;
-                        ticks_left = 0;
-                        break;
-                    }
-
-                    if chip8.wait_vblank {
-                        chip8.wait_vblank = false;
-                        ticks_left = 0;
-                        break;
-                    }
-                }
+            if run_frame(
+                &mut chip8,
+                &mut breakpoints,
+                &mut watchpoints,
+                &mut system.run_until,
+                system.ins_per_frame,
+                &mut ticks_left,
+            ) {
+                let screen = Screen::new(&chip8);
+                screen_renderer.advance_filter(&screen);
+                system.recorder.capture(&screen, &system.palette);
             }
 
-            if ticks_left <= 0 {
-                ticks_left = system.ins_per_frame;
-                if chip8.delay != 0 {
-                    chip8.delay -= 1;
+            if input.key_pressed(VirtualKeyCode::F12) {
+                system.screenshot_pressed = true;
+            }
+            if system.screenshot_pressed {
+                system.screenshot_pressed = false;
+                let path = timestamped_path("screenshot", "png");
+                let screen = Screen::new(&chip8);
+                match save_screenshot(
+                    &path,
+                    &screen,
+                    &system.palette,
+                    &screen_renderer,
+                    system.capture_scale,
+                ) {
+                    Ok(()) => info!("Saved {}", path.to_string_lossy()),
+                    Err(err) => error!("Couldn't save {}: {}", path.to_string_lossy(), err),
                 }
-                if chip8.sound != 0 {
-                    chip8.sound -= 1;
-                    if chip8.sound == 0 {
-                        // todo: stop beep
-                    }
+            }
+            if system.export_octo_pressed {
+                system.export_octo_pressed = false;
+                let prefix = system
+                    .rom_path
+                    .as_ref()
+                    .and_then(|path| path.file_stem())
+                    .map_or(String::from("rom"), |stem| {
+                        stem.to_string_lossy().to_string()
+                    });
+                let path = timestamped_path(&prefix, "8o");
+                // Export the rom as loaded, not as it's been changed by running
+                let mut loaded = Chip8::new();
+                loaded.copy_quirks(&chip8);
+                loaded.load_rom(rom.clone());
+                match fs::write(&path, octo_export::export(&loaded)) {
+                    Ok(()) => info!("Saved {}", path.to_string_lossy()),
+                    Err(err) => error!("Couldn't save {}: {}", path.to_string_lossy(), err),
                 }
             }
 
@@ -215,33 +477,42 @@ fn main() -> Result<(), Error> {
         }
 
         match event {
+            Event::WindowEvent {
+                event: WindowEvent::DroppedFile(path),
+                ..
+            } => {
+                system.open_rom = Some(path);
+            }
             Event::WindowEvent { event, .. } => {
                 // Update egui inputs
                 framework.handle_event(&event);
             }
             // Draw the current frame
             Event::RedrawRequested(_) => {
-                // Draw the world
-                chip8.draw(&mut pixels.frame_mut());
-                disassembler.prepare(&chip8);
+                // Draw the world, which egui shows as a texture
+                let screen = Screen::new(&chip8);
+                let screen_size = screen_renderer.size(&screen);
+                screen_frame.resize(screen_size.0 * screen_size.1 * 4, 0);
+                screen_renderer.render_filtered(&screen, &system.palette, &mut screen_frame);
 
                 // Prepare egui
                 framework.prepare(
                     &window,
                     &mut chip8,
-                    &disassembler,
+                    &mut disassembler,
                     &mut breakpoints,
                     &mut mem_editor,
                     &mut vram_editor,
                     &mut watchpoints,
+                    &mut recent_roms,
+                    &mut screen_renderer,
+                    &screen_frame,
+                    screen_size,
                     &mut system,
                 );
 
                 // Render everything together
                 let render_result = pixels.render_with(|encoder, render_target, context| {
-                    // Render the world texture
-                    context.scaling_renderer.render(encoder, render_target);
-
                     // Render egui
                     framework.render(encoder, render_target, context);
 
@@ -0,0 +1,217 @@
+use crate::constants::{MEGA_HEIGHT, MEGA_WIDTH};
+
+use std::cmp::min;
+
+/// How `080n` combines sprite pixels with what's already on screen.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BlendMode {
+    Normal,
+    Percent25,
+    Percent50,
+    Add,
+    Multiply,
+}
+
+impl BlendMode {
+    pub fn from_n(n: u8) -> Self {
+        match n {
+            1 => BlendMode::Percent25,
+            2 => BlendMode::Percent50,
+            3 => BlendMode::Add,
+            4 => BlendMode::Multiply,
+            _ => BlendMode::Normal,
+        }
+    }
+
+    fn blend(&self, dst: u8, src: u8) -> u8 {
+        let (dst, src) = (dst as u16, src as u16);
+        let ret = match self {
+            BlendMode::Normal => src,
+            BlendMode::Percent25 => (dst * 3 + src) / 4,
+            BlendMode::Percent50 => (dst + src) / 2,
+            BlendMode::Add => min(dst + src, 0xff),
+            BlendMode::Multiply => dst * src / 0xff,
+        };
+        ret as u8
+    }
+}
+
+/// A digitised sound started by `060n`. Only its state is kept, it isn't played yet.
+#[derive(Clone, Copy)]
+pub struct MegaSound {
+    pub addr: usize,
+    pub rate: u16,
+    pub len: usize,
+    pub looping: bool,
+}
+
+/// MEGA-CHIP's 256x192 256-colour display, drawn to alongside the SCHIP vram.
+pub struct MegaChip {
+    /// Set by `0011`, cleared by `0010`.
+    pub enabled: bool,
+    /// ARGB colours, of which `02nn` loads entries 1 to nn.
+    pub palette: [[u8; 4]; 256],
+    pub sprite_width: usize,
+    pub sprite_height: usize,
+    pub blend: BlendMode,
+    pub collision_color: u8,
+    /// Opacity of the whole screen, set by `05nn`.
+    pub alpha: u8,
+    pub sound: Option<MegaSound>,
+
+    /// Palette index of each pixel being drawn, to check collisions against.
+    index: Box<[u8]>,
+    /// RGBA of each pixel being drawn.
+    rgba: Box<[u8]>,
+    /// RGBA of the last finished frame, which is shown until the next `00E0`.
+    pub screen: Box<[u8]>,
+}
+
+impl MegaChip {
+    pub fn new() -> Self {
+        let mut palette = [[0xff, 0, 0, 0]; 256];
+        palette[0xff] = [0xff; 4];
+        let mut ret = Self {
+            enabled: false,
+            palette,
+            sprite_width: 0,
+            sprite_height: 0,
+            blend: BlendMode::Normal,
+            collision_color: 0,
+            alpha: 0xff,
+            sound: None,
+
+            index: vec![0; MEGA_WIDTH * MEGA_HEIGHT].into_boxed_slice(),
+            rgba: vec![0; MEGA_WIDTH * MEGA_HEIGHT * 4].into_boxed_slice(),
+            screen: vec![0; MEGA_WIDTH * MEGA_HEIGHT * 4].into_boxed_slice(),
+        };
+        ret.clear();
+        ret.screen.copy_from_slice(&ret.rgba);
+        ret
+    }
+
+    /// The palette index of each pixel of the frame being drawn.
+    pub fn index(&self) -> &[u8] {
+        &self.index
+    }
+
+    /// Show the frame drawn so far, then start a new one.
+    pub fn present(&mut self) {
+        self.screen.copy_from_slice(&self.rgba);
+        self.clear();
+    }
+
+    fn clear(&mut self) {
+        self.index.fill(0);
+        for pix in self.rgba.chunks_exact_mut(4) {
+            pix.copy_from_slice(&[0, 0, 0, 0xff]);
+        }
+    }
+
+    /// Load `count` ARGB colours from `addr` into palette entries 1 onwards.
+    pub fn load_palette(&mut self, mem: &[u8], addr: usize, count: usize) {
+        for i in 0..count {
+            for j in 0..4 {
+                self.palette[i + 1][j] = read(mem, addr + i * 4 + j);
+            }
+        }
+    }
+
+    /// Start the sample at `addr`: a 16-bit rate and 24-bit length, a 0 byte, then the data.
+    pub fn play_sound(&mut self, mem: &[u8], addr: usize, looping: bool) {
+        let rate = (read(mem, addr) as u16) << 8 | read(mem, addr + 1) as u16;
+        let len = (read(mem, addr + 2) as usize) << 16
+            | (read(mem, addr + 3) as usize) << 8
+            | read(mem, addr + 4) as usize;
+        self.sound = Some(MegaSound {
+            addr: addr + 6,
+            rate,
+            len,
+            looping,
+        });
+    }
+
+    /// Draw a pixel in palette colour `color`, returning true if it covered the collision colour.
+    fn plot(&mut self, x: usize, y: usize, color: u8) -> bool {
+        if x >= MEGA_WIDTH || y >= MEGA_HEIGHT {
+            return false;
+        }
+        let offs = y * MEGA_WIDTH + x;
+        let collided = self.index[offs] == self.collision_color;
+        self.index[offs] = color;
+
+        let [_, r, g, b] = self.palette[color as usize];
+        let pix = &mut self.rgba[offs * 4..offs * 4 + 4];
+        pix[0] = self.blend.blend(pix[0], r);
+        pix[1] = self.blend.blend(pix[1], g);
+        pix[2] = self.blend.blend(pix[2], b);
+        collided
+    }
+
+    /// Draw a sprite of `sprite_width` x `sprite_height` palette indexes, where 0 is transparent.
+    pub fn draw_sprite(&mut self, mem: &[u8], addr: usize, x: usize, y: usize) -> bool {
+        let width = if self.sprite_width == 0 {
+            256
+        } else {
+            self.sprite_width
+        };
+        let height = if self.sprite_height == 0 {
+            256
+        } else {
+            self.sprite_height
+        };
+        let mut collided = false;
+        for row in 0..height {
+            for col in 0..width {
+                let color = read(mem, addr + row * width + col);
+                if color != 0 {
+                    collided |= self.plot(x + col, y + row, color);
+                }
+            }
+        }
+        collided
+    }
+
+    /// Draw an 8-pixel wide 1-bit sprite, eg from the font, in palette colour 255.
+    pub fn draw_bits(&mut self, mem: &[u8], addr: usize, x: usize, y: usize, rows: usize) -> bool {
+        let mut collided = false;
+        for row in 0..rows {
+            let byte = read(mem, addr + row);
+            for col in 0..8 {
+                if byte & (0x80 >> col) != 0 {
+                    collided |= self.plot(x + col, y + row, 0xff);
+                }
+            }
+        }
+        collided
+    }
+
+    /// Move the frame being drawn by `dx`, `dy` pixels, clearing what's scrolled in.
+    pub fn scroll(&mut self, dx: isize, dy: isize) {
+        let old_index = self.index.clone();
+        let old_rgba = self.rgba.clone();
+        self.clear();
+        for y in 0..MEGA_HEIGHT {
+            for x in 0..MEGA_WIDTH {
+                let src_x = x as isize - dx;
+                let src_y = y as isize - dy;
+                if src_x < 0 || src_y < 0 {
+                    continue;
+                }
+                let (src_x, src_y) = (src_x as usize, src_y as usize);
+                if src_x >= MEGA_WIDTH || src_y >= MEGA_HEIGHT {
+                    continue;
+                }
+                let src = src_y * MEGA_WIDTH + src_x;
+                let dst = y * MEGA_WIDTH + x;
+                self.index[dst] = old_index[src];
+                self.rgba[dst * 4..dst * 4 + 4].copy_from_slice(&old_rgba[src * 4..src * 4 + 4]);
+            }
+        }
+    }
+}
+
+// MEGA-CHIP addresses are 24-bit, so may point past the end of memory
+fn read(mem: &[u8], addr: usize) -> u8 {
+    mem.get(addr).copied().unwrap_or(0)
+}
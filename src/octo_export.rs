@@ -0,0 +1,213 @@
+use crate::analysis::{Analysis, ByteKind};
+use crate::chip8::{Chip8, Chip8System};
+use crate::disassembler::{decode, InsTokenType};
+
+use std::fmt::Write;
+
+/// Octo has no syntax for other systems' extra opcodes, so those stay as bytes.
+fn octo_supported(chip8: &Chip8, op: u16) -> bool {
+    let nn = op & 0xff;
+    match op >> 12 {
+        0x0 => matches!(op, 0x00c0..=0x00df | 0x00e0 | 0x00ee | 0x00fb..=0x00ff),
+        0x5 if chip8.system == Chip8System::CHIP8X => op & 0xf == 0,
+        0xb => chip8.system != Chip8System::CHIP8X,
+        0xe if chip8.system == Chip8System::CHIP8X => !matches!(nn, 0xf2 | 0xf5),
+        0xf if chip8.system == Chip8System::CHIP8X => !matches!(nn, 0xf8 | 0xfb),
+        _ => true,
+    }
+}
+
+fn format_token(token: InsTokenType, name: &dyn Fn(u16) -> Option<String>) -> String {
+    match token {
+        InsTokenType::KeyWord(kw) => kw,
+        InsTokenType::Const24(val) => format!("0x{:06X}", val),
+        InsTokenType::Const16(val) => format!("0x{:04X}", val),
+        InsTokenType::Const12(val) => format!("0x{:03X}", val),
+        InsTokenType::Const8(val) => format!("0x{:02X}", val),
+        InsTokenType::Const4(val) => format!("0x{:X}", val),
+        InsTokenType::Target(addr) => name(addr).unwrap_or_else(|| format!("0x{:03X}", addr)),
+        InsTokenType::VReg(reg) => format!("v{:x}", reg),
+        InsTokenType::IReg => String::from("i"),
+        InsTokenType::Operator(op) => op,
+    }
+}
+
+/// The instruction at `addr` in Octo syntax and its length, or None if Octo can't
+/// express it. `name` gives the label to use for an address, if any.
+fn instruction_text(
+    chip8: &Chip8,
+    addr: u16,
+    name: &dyn Fn(u16) -> Option<String>,
+) -> Option<(String, u16)> {
+    let op = ((chip8.mem[addr as usize] as u16) << 8) | (chip8.mem[addr as usize + 1] as u16);
+    let (tokens, next) = decode(chip8, addr, true);
+    if tokens.is_empty() || !octo_supported(chip8, op) {
+        return None;
+    }
+    let words: Vec<String> = tokens
+        .into_iter()
+        .map(|token| format_token(token, name))
+        .collect();
+    Some((words.join(" "), next - addr))
+}
+
+/// The quirks as the options Octo has for them, so the source runs the same there.
+fn options_header(chip8: &Chip8) -> String {
+    let quirks = &chip8.quirks;
+    let options = [
+        ("shiftQuirks", quirks.shifting.to_string()),
+        ("loadStoreQuirks", (!quirks.memory).to_string()),
+        ("jumpQuirks", quirks.jumping.to_string()),
+        ("logicQuirks", quirks.vf_reset.to_string()),
+        ("clipQuirks", quirks.clipping.to_string()),
+        ("vBlankQuirks", quirks.disp_wait.to_string()),
+        (
+            "maxSize",
+            chip8
+                .mem_size
+                .saturating_sub(chip8.start_address as usize)
+                .to_string(),
+        ),
+    ];
+    let mut ret = format!(
+        "# {} rom, exported with these Octo options:\n",
+        chip8.profile_name
+    );
+    for (name, value) in options {
+        let _ = writeln!(ret, "# {}: {}", name, value);
+    }
+    ret
+}
+
+/// The loaded rom as Octo source. Code found by tracing from the entry point becomes
+/// instructions, and the rest `:byte`s, with labels for what's jumped to, called and
+/// pointed to by `i`.
+pub fn export(chip8: &Chip8) -> String {
+    let analysis = Analysis::new(chip8, []);
+    let start = chip8.start_address as usize;
+    let end = start + chip8.rom_len;
+    // pc can't go past 64K, so nothing after it was analysed
+    let kind = |addr: usize| match u16::try_from(addr) {
+        Ok(addr) => analysis.kind(addr),
+        Err(_) => ByteKind::Unknown,
+    };
+    // Only labels on lines that get written out can be referred to
+    let name = |addr: u16| {
+        let addr_usize = addr as usize;
+        if addr_usize < start || addr_usize >= end || kind(addr_usize) == ByteKind::CodeTail {
+            return None;
+        }
+        analysis.label(addr)
+    };
+
+    let mut ret = options_header(chip8);
+    let mut addr = start;
+    while addr < end {
+        if let Some(label) = u16::try_from(addr).ok().and_then(&name) {
+            let _ = write!(ret, "\n: {}\n", label);
+        }
+
+        if let ByteKind::Code(len) = kind(addr) {
+            let len = len as usize;
+            if addr + len <= end {
+                match instruction_text(chip8, addr as u16, &name) {
+                    Some((text, _)) => {
+                        let _ = writeln!(ret, "\t{}", text);
+                    }
+                    None => {
+                        // Keep what the emulator runs it as, for reading
+                        let (tokens, _) = decode(chip8, addr as u16, false);
+                        let words: Vec<String> = tokens
+                            .into_iter()
+                            .map(|token| format_token(token, &|_| None))
+                            .collect();
+                        ret.push('\t');
+                        for byte in &chip8.mem[addr..addr + len] {
+                            let _ = write!(ret, ":byte 0x{:02X} ", byte);
+                        }
+                        if words.is_empty() {
+                            ret.push('\n');
+                        } else {
+                            let _ = writeln!(ret, "# {}", words.join(" "));
+                        }
+                    }
+                }
+                addr += len;
+                continue;
+            }
+        }
+
+        let _ = writeln!(ret, "\t:byte 0b{:08b}", chip8.mem[addr]);
+        addr += 1;
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::profiles::Profile;
+
+    #[test]
+    fn max_size_is_zero_past_the_end_of_memory() {
+        let mut chip8 = Chip8::new();
+        chip8.mem_size = 0x200;
+        chip8.start_address = 0x300;
+        assert!(options_header(&chip8).contains("# maxSize: 0\n"));
+    }
+
+    #[test]
+    fn every_opcode_reassembles() {
+        let mut chip8 = Chip8::new();
+        chip8.set_profile(&Profile::new());
+        for op in 0..=0xffff_u16 {
+            chip8.mem[0x200] = (op >> 8) as u8;
+            chip8.mem[0x201] = op as u8;
+            if let Some((text, 2)) = instruction_text(&chip8, 0x200, &|_| None) {
+                let source = format!(": main {}", text);
+                let rom = assemble(&source, 0x200).unwrap().rom;
+                assert_eq!(rom, op.to_be_bytes(), "'{}'", text);
+            }
+        }
+    }
+
+    #[test]
+    fn export_reassembles_to_the_same_rom() {
+        #[rustfmt::skip]
+        let rom = vec![
+            0x00, 0xe0,             // 200: clear
+            0x6a, 0x05,             // 202: va := 5
+            0xa2, 0x20,             // 204: i := data
+            0x22, 0x14,             // 206: call sub
+            0x3a, 0x00,             // 208: if va != 0 then
+            0xf0, 0x00, 0x02, 0x30, // 20a: i := long 0x230
+            0xd0, 0x15,             // 20e: sprite v0 v1 5
+            0x8a, 0xb6,             // 210: va >>= vb, only va with the shift quirk
+            0x12, 0x08,             // 212: jump 208
+            0x7a, 0xff,             // 214: va += 0xff
+            0x01, 0x23,             // 216: not an XO-CHIP opcode
+            0xb2, 0x1c,             // 218: jump0 21c, jump v2 21c with the jump quirk
+            0x00, 0xee,             // 21a: never reached
+            0x00, 0xee,             // 21c: return
+            0x80, 0x06,             // 21e: data
+            0x3c, 0x42, 0x81, 0x42, 0x3c, // 220: a sprite
+            0xaa,                   // an odd length
+        ];
+
+        let mut chip8 = Chip8::new();
+        chip8.set_profile(&Profile::new());
+        chip8.quirks.shifting = true;
+        chip8.quirks.jumping = true;
+        chip8.load_rom(rom.clone());
+
+        let source = export(&chip8);
+        assert!(source.contains(": sub_214"));
+        assert!(source.contains("call sub_214"));
+        assert!(source.contains("i := data_220"));
+        assert!(source.contains("jump0 label_21c"));
+        assert!(source.contains("va >>= vb"));
+
+        assert_eq!(assemble(&source, 0x200).unwrap().rom, rom);
+    }
+}
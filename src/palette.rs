@@ -0,0 +1,108 @@
+use serde::{Deserialize, Serialize};
+
+// The 16 colours Octo uses for XO-CHIP's 4-plane mode
+const OCTO_16: [u32; 16] = [
+    0x000000, 0xffffff, 0xaaaaaa, 0x555555, 0xff0000, 0x00ff00, 0x0000ff, 0xffff00, 0x880000,
+    0x008800, 0x000088, 0x888800, 0xff00ff, 0x00ffff, 0x880088, 0x008888,
+];
+
+// Background, plane 1, plane 2, both planes
+const PRESETS: [(&str, [u32; 4]); 10] = [
+    ("Default", [0x222222, 0xffffff, 0x0044aa, 0xaa5500]),
+    ("Octo", [0x996600, 0xffcc00, 0xff6600, 0x662200]),
+    ("Octo LCD", [0x0f380f, 0x8bac0f, 0x306230, 0x9bbc0f]),
+    ("Octo Hotdog", [0x000000, 0xff0000, 0xffff00, 0xffffff]),
+    ("Octo Gray", [0xaaaaaa, 0x000000, 0xffffff, 0x666666]),
+    ("Octo CGA 0", [0x000000, 0x00ff00, 0xff0000, 0xffff00]),
+    ("Octo CGA 1", [0x000000, 0xff00ff, 0x00ffff, 0xffffff]),
+    ("LCD green", [0x9bbc0f, 0x0f380f, 0x306230, 0x8bac0f]),
+    ("Amber", [0x1a0f00, 0xffb000, 0xb36b00, 0xffd77a]),
+    ("Green phosphor", [0x001400, 0x33ff33, 0x1a801a, 0x99ff99]),
+];
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct Palette {
+    pub name: String,
+    /// Used with 2 planes: background, plane 1, plane 2, both planes.
+    pub colors_4: [[u8; 3]; 4],
+    /// Used with 4 planes, indexed by the vram value.
+    pub colors_16: [[u8; 3]; 16],
+}
+
+fn rgb(color: u32) -> [u8; 3] {
+    [(color >> 16) as u8, (color >> 8) as u8, color as u8]
+}
+
+fn parse_color(text: &str) -> Result<[u8; 3], String> {
+    let hex = text.trim_start_matches('#');
+    if hex.len() != 6 {
+        return Err(format!("Invalid colour '{}', expected RRGGBB", text));
+    }
+    match u32::from_str_radix(hex, 16) {
+        Ok(color) => Ok(rgb(color)),
+        Err(_) => Err(format!("Invalid colour '{}', expected RRGGBB", text)),
+    }
+}
+
+impl Palette {
+    pub fn new() -> Self {
+        Self::presets().remove(0)
+    }
+
+    pub fn presets() -> Vec<Palette> {
+        PRESETS
+            .iter()
+            .map(|(name, colors)| Palette {
+                name: name.to_string(),
+                colors_4: colors.map(rgb),
+                colors_16: OCTO_16.map(rgb),
+            })
+            .collect()
+    }
+
+    /// Parse a list of `#RRGGBB` colours separated by spaces or commas, as in Octo's
+    /// palette options. 4 colours set the 2-plane palette, 16 colours set both palettes.
+    pub fn from_octo_str(name: &str, text: &str) -> Result<Palette, String> {
+        let mut colors = vec![];
+        for part in text.split(|c: char| c.is_whitespace() || c == ',') {
+            if !part.is_empty() {
+                colors.push(parse_color(part)?);
+            }
+        }
+
+        let mut ret = Self::new();
+        ret.name = name.to_string();
+        match colors.len() {
+            4 => {
+                ret.colors_4.copy_from_slice(&colors);
+            }
+            16 => {
+                ret.colors_4.copy_from_slice(&colors[..4]);
+                ret.colors_16.copy_from_slice(&colors);
+            }
+            len => {
+                return Err(format!("Expected 4 or 16 colours, got {}", len));
+            }
+        }
+        Ok(ret)
+    }
+
+    /// The 2-plane palette in the format accepted by `from_octo_str`.
+    pub fn to_octo_str(&self) -> String {
+        self.colors_4
+            .iter()
+            .map(|c| format!("#{:02X}{:02X}{:02X}", c[0], c[1], c[2]))
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    /// RGBA for a vram value.
+    pub fn color(&self, c: u8, colors_16: bool) -> [u8; 4] {
+        let [r, g, b] = if colors_16 {
+            self.colors_16[(c & 0xf) as usize]
+        } else {
+            self.colors_4[(c & 3) as usize]
+        };
+        [r, g, b, 0xff]
+    }
+}
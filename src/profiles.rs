@@ -0,0 +1,368 @@
+use crate::chip8::{Chip8System, Quirks};
+use crate::constants::PROFILES_FNAME;
+use crate::fonts::FontSet;
+use crate::settings::config_path;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+/// The size of `Chip8::stack`.
+const MAX_STACK_DEPTH: usize = 16;
+/// MEGA-CHIP's 24-bit address space.
+const MAX_MEM_SIZE: usize = 0x1000000;
+
+// The COSMAC VIP's original interpreter
+const VIP_QUIRKS: Quirks = Quirks {
+    vf_reset: true,
+    memory: true,
+    disp_wait: true,
+    clipping: true,
+    shifting: false,
+    jumping: false,
+    disp_wait_lores: true,
+    scroll_full_lores: true,
+    colors_16: true,
+};
+
+// SCHIP on the HP48
+const LEGACY_SCHIP_QUIRKS: Quirks = Quirks {
+    vf_reset: false,
+    memory: false,
+    disp_wait: true,
+    clipping: true,
+    shifting: true,
+    jumping: true,
+    disp_wait_lores: true,
+    scroll_full_lores: false,
+    colors_16: true,
+};
+
+// SCHIP as most modern interpreters run it
+const MODERN_SCHIP_QUIRKS: Quirks = Quirks {
+    vf_reset: false,
+    memory: false,
+    disp_wait: true,
+    clipping: true,
+    shifting: true,
+    jumping: true,
+    disp_wait_lores: false,
+    scroll_full_lores: true,
+    colors_16: true,
+};
+
+// SCHPC, which runs SCHIP's opcodes with CHIP-8's behaviour
+const SCHPC_QUIRKS: Quirks = Quirks {
+    vf_reset: false,
+    memory: true,
+    disp_wait: false,
+    clipping: true,
+    shifting: false,
+    jumping: false,
+    disp_wait_lores: false,
+    scroll_full_lores: true,
+    colors_16: true,
+};
+
+const XOCHIP_QUIRKS: Quirks = Quirks {
+    vf_reset: false,
+    memory: true,
+    disp_wait: true,
+    clipping: false,
+    shifting: false,
+    jumping: false,
+    disp_wait_lores: false,
+    scroll_full_lores: true,
+    colors_16: true,
+};
+
+// Octo's defaults, which don't wait for vblank
+const OCTO_QUIRKS: Quirks = Quirks {
+    vf_reset: false,
+    memory: true,
+    disp_wait: false,
+    clipping: false,
+    shifting: false,
+    jumping: false,
+    disp_wait_lores: false,
+    scroll_full_lores: true,
+    colors_16: true,
+};
+
+const MEGACHIP_QUIRKS: Quirks = Quirks {
+    vf_reset: false,
+    memory: false,
+    disp_wait: false,
+    clipping: true,
+    shifting: true,
+    jumping: true,
+    disp_wait_lores: false,
+    scroll_full_lores: false,
+    colors_16: true,
+};
+
+// Name, opcode set, start address, memory size, stack depth, quirks and font
+const PRESETS: [(&str, Chip8System, u16, usize, usize, Quirks, FontSet); 12] = [
+    (
+        "CHIP-8",
+        Chip8System::CHIP8,
+        0x200,
+        0x1000,
+        12,
+        VIP_QUIRKS,
+        FontSet::Vip,
+    ),
+    (
+        "CHIP-8X",
+        Chip8System::CHIP8X,
+        0x300,
+        0x1000,
+        12,
+        VIP_QUIRKS,
+        FontSet::Vip,
+    ),
+    (
+        "HiRes CHIP-8",
+        Chip8System::CHIP8HIRES,
+        0x200,
+        0x1000,
+        12,
+        VIP_QUIRKS,
+        FontSet::Vip,
+    ),
+    (
+        "DREAM 6800",
+        Chip8System::CHIP8,
+        0x200,
+        0x1000,
+        12,
+        VIP_QUIRKS,
+        FontSet::Dream6800,
+    ),
+    (
+        "ETI-660",
+        Chip8System::CHIP8,
+        0x600,
+        0x1000,
+        12,
+        VIP_QUIRKS,
+        FontSet::Eti660,
+    ),
+    (
+        "SCHIP 1.0",
+        Chip8System::SCHIP10,
+        0x200,
+        0x1000,
+        16,
+        LEGACY_SCHIP_QUIRKS,
+        FontSet::Schip11,
+    ),
+    (
+        "SCHIP 1.1",
+        Chip8System::LSCHIP,
+        0x200,
+        0x1000,
+        16,
+        LEGACY_SCHIP_QUIRKS,
+        FontSet::Schip11,
+    ),
+    (
+        "Modern SCHIP",
+        Chip8System::MSCHIP,
+        0x200,
+        0x1000,
+        16,
+        MODERN_SCHIP_QUIRKS,
+        FontSet::Octo,
+    ),
+    (
+        "SCHPC",
+        Chip8System::MSCHIP,
+        0x200,
+        0x1000,
+        16,
+        SCHPC_QUIRKS,
+        FontSet::Octo,
+    ),
+    (
+        "XO-CHIP",
+        Chip8System::XOCHIP,
+        0x200,
+        0x10000,
+        16,
+        XOCHIP_QUIRKS,
+        FontSet::Octo,
+    ),
+    (
+        "Octo XO-CHIP",
+        Chip8System::XOCHIP,
+        0x200,
+        0x10000,
+        16,
+        OCTO_QUIRKS,
+        FontSet::Octo,
+    ),
+    (
+        "MEGA-CHIP",
+        Chip8System::MEGACHIP,
+        0x200,
+        0x1000000,
+        16,
+        MEGACHIP_QUIRKS,
+        FontSet::Octo,
+    ),
+];
+
+/// Everything that makes up an emulated machine, picked as one in the Controls window.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    /// The opcodes understood on top of CHIP-8's.
+    pub system: Chip8System,
+    /// Where roms are loaded and started.
+    pub start_address: u16,
+    /// Addressable memory in bytes.
+    pub mem_size: usize,
+    /// Nesting depth of `call`s.
+    pub stack_depth: usize,
+    pub quirks: Quirks,
+    pub font: FontSet,
+}
+
+/// The layout of the profiles file, a `[[profiles]]` table per profile.
+#[derive(Serialize, Deserialize)]
+struct ProfilesFile {
+    profiles: Vec<Profile>,
+}
+
+impl Profile {
+    /// The XO-CHIP preset, which new instances start with.
+    pub fn new() -> Self {
+        Self::presets()
+            .into_iter()
+            .find(|profile| profile.system == Chip8System::XOCHIP)
+            .unwrap()
+    }
+
+    pub fn presets() -> Vec<Profile> {
+        PRESETS
+            .iter()
+            .map(
+                |&(name, system, start_address, mem_size, stack_depth, quirks, font)| Profile {
+                    name: name.to_string(),
+                    system,
+                    start_address,
+                    mem_size,
+                    stack_depth,
+                    quirks,
+                    font,
+                },
+            )
+            .collect()
+    }
+
+    /// Check a profile from a file, as the emulator can't run with any values.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.stack_depth == 0 || self.stack_depth > MAX_STACK_DEPTH {
+            return Err(format!(
+                "{}: stack_depth must be from 1 to {}",
+                self.name, MAX_STACK_DEPTH
+            ));
+        }
+        let start = self.start_address as usize;
+        if self.mem_size <= start || self.mem_size > MAX_MEM_SIZE {
+            return Err(format!(
+                "{}: mem_size must be over the start_address {:#x} and at most {:#x}",
+                self.name, start, MAX_MEM_SIZE
+            ));
+        }
+        Ok(())
+    }
+
+    /// The presets followed by the user's own valid profiles from the profiles file, if any.
+    pub fn load_all() -> Vec<Profile> {
+        let mut ret = Self::presets();
+        let path = config_path(PROFILES_FNAME);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(_) => return ret,
+        };
+        let file = match toml::from_str::<ProfilesFile>(&contents) {
+            Ok(file) => file,
+            Err(err) => {
+                warn!("Ignoring invalid {}: {}", path.to_string_lossy(), err);
+                return ret;
+            }
+        };
+        for profile in file.profiles {
+            match profile.validate() {
+                Ok(()) => ret.push(profile),
+                Err(err) => warn!("Ignoring profile in {}: {}", path.to_string_lossy(), err),
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_are_valid() {
+        for profile in Profile::presets() {
+            assert_eq!(profile.validate(), Ok(()), "{}", profile.name);
+        }
+        assert_eq!(Profile::new().name, "XO-CHIP");
+    }
+
+    #[test]
+    fn user_profiles_parse() {
+        let file: ProfilesFile = toml::from_str(
+            r#"
+            [[profiles]]
+            name = "Tiny"
+            system = "CHIP8"
+            start_address = 0x200
+            mem_size = 0x800
+            stack_depth = 2
+            font = "Octo"
+
+            [profiles.quirks]
+            vf_reset = true
+            memory = true
+            disp_wait = true
+            clipping = true
+            shifting = false
+            jumping = false
+            disp_wait_lores = true
+            scroll_full_lores = true
+            colors_16 = true
+            "#,
+        )
+        .unwrap();
+        let profile = &file.profiles[0];
+        assert_eq!(profile.name, "Tiny");
+        assert_eq!((profile.mem_size, profile.stack_depth), (0x800, 2));
+        assert!(profile.quirks == VIP_QUIRKS);
+        assert_eq!(profile.validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_rejects_unusable_sizes() {
+        let mut profile = Profile::new();
+        for stack_depth in [0, 17] {
+            profile.stack_depth = stack_depth;
+            assert!(profile.validate().is_err());
+        }
+        profile.stack_depth = 16;
+        for mem_size in [0, 0x200, 0x1000001] {
+            profile.mem_size = mem_size;
+            assert!(profile.validate().is_err());
+        }
+        profile.mem_size = 0x201;
+        assert_eq!(profile.validate(), Ok(()));
+        profile.start_address = 0x600;
+        assert!(profile.validate().is_err());
+    }
+}
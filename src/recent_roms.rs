@@ -0,0 +1,55 @@
+use crate::constants::{MAX_RECENT_ROMS, RECENT_ROMS_FNAME};
+use crate::settings::config_path;
+
+use log::warn;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub struct RecentRoms {
+    pub paths: Vec<PathBuf>,
+}
+
+impl RecentRoms {
+    pub fn new() -> Self {
+        Self { paths: vec![] }
+    }
+
+    /// Load the list from disk, one path per line. A missing file is an empty list.
+    pub fn load() -> Self {
+        let mut ret = Self::new();
+        if let Ok(contents) = fs::read_to_string(config_path(RECENT_ROMS_FNAME)) {
+            for line in contents.lines() {
+                if !line.is_empty() {
+                    ret.paths.push(PathBuf::from(line));
+                }
+            }
+        }
+        ret.paths.truncate(MAX_RECENT_ROMS);
+        ret
+    }
+
+    pub fn save(&self) {
+        let mut contents = String::new();
+        for path in &self.paths {
+            contents.push_str(&path.to_string_lossy());
+            contents.push('\n');
+        }
+        if let Err(err) = fs::write(config_path(RECENT_ROMS_FNAME), contents) {
+            warn!("Couldn't save {}: {}", RECENT_ROMS_FNAME, err);
+        }
+    }
+
+    /// Move `path` to the top of the list, dropping the oldest entry if full.
+    pub fn add(&mut self, path: &Path) {
+        let path = fs::canonicalize(path).unwrap_or(path.to_path_buf());
+        self.paths.retain(|p| *p != path);
+        self.paths.insert(0, path);
+        self.paths.truncate(MAX_RECENT_ROMS);
+        self.save();
+    }
+
+    pub fn clear(&mut self) {
+        self.paths.clear();
+        self.save();
+    }
+}
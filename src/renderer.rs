@@ -0,0 +1,378 @@
+use crate::chip8::{Chip8, Chip8System};
+use crate::chip8x::Chip8X;
+use crate::constants::{HEIGHT, MEGA_HEIGHT, MEGA_WIDTH, WIDTH};
+use crate::megachip::MegaChip;
+use crate::palette::Palette;
+
+/// The display state needed to produce an image, borrowed from a `Chip8`.
+pub struct Screen<'a> {
+    pub vram: &'a [u8],
+    pub hires: bool,
+    /// HiRes CHIP-8's 64x64 mode, where lores pixels are only doubled in width.
+    pub hires64: bool,
+    pub colors_16: bool,
+    /// Set while MEGA-CHIP's display is shown instead of vram.
+    pub mega: Option<&'a MegaChip>,
+    /// Set for CHIP-8X, whose colours come from its colour board.
+    pub c8x: Option<&'a Chip8X>,
+}
+
+impl<'a> Screen<'a> {
+    pub fn new(chip8: &'a Chip8) -> Self {
+        Self {
+            vram: &chip8.vram,
+            hires: chip8.hires,
+            hires64: chip8.hires64,
+            colors_16: chip8.quirks.colors_16,
+            mega: if chip8.mega.enabled {
+                Some(&chip8.mega)
+            } else {
+                None
+            },
+            c8x: if chip8.system == Chip8System::CHIP8X {
+                Some(&chip8.c8x)
+            } else {
+                None
+            },
+        }
+    }
+
+    /// RGBA of the vram byte `c` at vram position `x`, `y`.
+    fn color(&self, palette: &Palette, c: u8, x: usize, y: usize) -> [u8; 4] {
+        match self.c8x {
+            Some(c8x) => c8x.color(c != 0, x / 2, y / 2),
+            None => palette.color(c, self.colors_16),
+        }
+    }
+}
+
+/// Ways to hide the flicker of sprites being erased and redrawn.
+#[derive(Clone, Copy, PartialEq)]
+pub enum FrameFilter {
+    None,
+    /// Lit pixels fade out over several frames.
+    Phosphor,
+    /// A pixel is lit if it was lit in this frame or the previous one.
+    OrLastTwo,
+}
+
+/// Where the game screen is drawn in the window.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ScreenPlacement {
+    /// Fills the window, behind the tool windows.
+    Background,
+    LeftPanel,
+    RightPanel,
+    BottomPanel,
+}
+
+/// Turns vram into RGBA pixels, for the window, image export or headless dumps.
+pub struct ScreenRenderer {
+    /// Output lores as 64x32 rather than doubled up to the 128x64 vram.
+    pub native_lores: bool,
+    pub filter: FrameFilter,
+    /// The part of a pixel's intensity kept each frame with `FrameFilter::Phosphor`.
+    pub decay: f32,
+    /// Only scale the screen by whole numbers, so all pixels are the same size.
+    pub integer_scaling: bool,
+    /// Keep pixels square rather than stretching the screen to fill its area.
+    pub keep_aspect: bool,
+    /// Colour around the screen when it doesn't fill its area.
+    pub border: [u8; 3],
+    pub placement: ScreenPlacement,
+
+    intensity: Vec<f32>,
+    last_lit: Vec<u8>,
+    /// Vram at the end of the last emulated frame, and of the one before it.
+    last_vram: Vec<u8>,
+    prev_vram: Vec<u8>,
+}
+
+impl ScreenRenderer {
+    pub fn new() -> Self {
+        Self {
+            native_lores: false,
+            filter: FrameFilter::None,
+            decay: 0.6,
+            integer_scaling: true,
+            keep_aspect: true,
+            border: [0, 0, 0],
+            placement: ScreenPlacement::Background,
+
+            intensity: vec![0.0; WIDTH * HEIGHT],
+            last_lit: vec![0; WIDTH * HEIGHT],
+            last_vram: vec![0; WIDTH * HEIGHT],
+            prev_vram: vec![0; WIDTH * HEIGHT],
+        }
+    }
+
+    /// Width and height of the image `render` produces for `screen`.
+    pub fn size(&self, screen: &Screen) -> (usize, usize) {
+        if screen.mega.is_some() {
+            (MEGA_WIDTH, MEGA_HEIGHT)
+        } else if self.native_lores && !screen.hires && screen.hires64 {
+            (WIDTH / 2, HEIGHT)
+        } else if self.native_lores && !screen.hires {
+            (WIDTH / 2, HEIGHT / 2)
+        } else {
+            (WIDTH, HEIGHT)
+        }
+    }
+
+    /// Size to show an image of `image_size` pixels at, within an area of `avail` pixels.
+    pub fn fit(&self, image_size: (usize, usize), avail: (f32, f32)) -> (f32, f32) {
+        let (width, height) = (image_size.0 as f32, image_size.1 as f32);
+        let mut scale_x = avail.0 / width;
+        let mut scale_y = avail.1 / height;
+        if self.keep_aspect {
+            scale_x = scale_x.min(scale_y);
+            scale_y = scale_x;
+        }
+        if self.integer_scaling {
+            scale_x = scale_x.floor().max(1.0);
+            scale_y = scale_y.floor().max(1.0);
+        }
+        (width * scale_x, height * scale_y)
+    }
+
+    /// Write RGBA pixels into `frame`, which must hold at least `size` pixels.
+    pub fn render(&self, screen: &Screen, palette: &Palette, frame: &mut [u8]) {
+        if let Some(mega) = screen.mega {
+            // Already RGBA, faded by the screen alpha
+            for (pix, src) in frame.chunks_exact_mut(4).zip(mega.screen.chunks_exact(4)) {
+                for ch in 0..3 {
+                    pix[ch] = (src[ch] as u16 * mega.alpha as u16 / 0xff) as u8;
+                }
+                pix[3] = 0xff;
+            }
+            return;
+        }
+
+        let (width, height) = self.size(screen);
+        let (step_x, step_y) = (WIDTH / width, HEIGHT / height);
+        let mut pixels = frame.chunks_exact_mut(4);
+        for y in 0..height {
+            for x in 0..width {
+                let (vx, vy) = (x * step_x, y * step_y);
+                let c = screen.vram[vy * WIDTH + vx];
+                let pix = pixels.next().unwrap();
+                pix.copy_from_slice(&screen.color(palette, c, vx, vy));
+            }
+        }
+    }
+
+    /// Move the filters on by an emulated frame, which ended showing `screen`.
+    pub fn advance_filter(&mut self, screen: &Screen) {
+        // The filters work on plane bits, which MEGA-CHIP's display doesn't have
+        if screen.mega.is_some() {
+            return;
+        }
+
+        for (i, &c) in screen.vram.iter().enumerate() {
+            if c != 0 {
+                self.intensity[i] = 1.0;
+                self.last_lit[i] = c;
+            } else {
+                self.intensity[i] *= self.decay;
+            }
+        }
+        self.prev_vram.copy_from_slice(&self.last_vram);
+        self.last_vram.copy_from_slice(screen.vram);
+    }
+
+    /// Like `render`, but applies `filter` as of the last `advance_filter`, so redrawing
+    /// without emulating a frame shows the same image.
+    pub fn render_filtered(&self, screen: &Screen, palette: &Palette, frame: &mut [u8]) {
+        if screen.mega.is_some() {
+            self.render(screen, palette, frame);
+            return;
+        }
+
+        let (width, height) = self.size(screen);
+        let (step_x, step_y) = (WIDTH / width, HEIGHT / height);
+        let mut pixels = frame.chunks_exact_mut(4);
+        for y in 0..height {
+            for x in 0..width {
+                let (vx, vy) = (x * step_x, y * step_y);
+                let i = vy * WIDTH + vx;
+                let c = screen.vram[i];
+                let pix = pixels.next().unwrap();
+
+                match self.filter {
+                    FrameFilter::None => {
+                        pix.copy_from_slice(&screen.color(palette, c, vx, vy));
+                    }
+                    FrameFilter::Phosphor => {
+                        if c != 0 {
+                            pix.copy_from_slice(&screen.color(palette, c, vx, vy));
+                        } else {
+                            let background = screen.color(palette, 0, vx, vy);
+                            let lit = screen.color(palette, self.last_lit[i], vx, vy);
+                            for ch in 0..4 {
+                                let diff = lit[ch] as f32 - background[ch] as f32;
+                                pix[ch] = (background[ch] as f32 + diff * self.intensity[i]) as u8;
+                            }
+                        }
+                    }
+                    FrameFilter::OrLastTwo => {
+                        let c_or = c | self.prev_vram[i];
+                        pix.copy_from_slice(&screen.color(palette, c_or, vx, vy));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen(vram: &[u8], hires: bool, colors_16: bool) -> Screen<'_> {
+        Screen {
+            vram,
+            hires,
+            hires64: false,
+            colors_16,
+            mega: None,
+            c8x: None,
+        }
+    }
+
+    fn test_palette() -> Palette {
+        let mut palette = Palette::new();
+        palette.colors_4 = [[0, 0, 0], [1, 2, 3], [4, 5, 6], [7, 8, 9]];
+        for (i, color) in palette.colors_16.iter_mut().enumerate() {
+            *color = [i as u8; 3];
+        }
+        palette
+    }
+
+    #[test]
+    fn lores_size() {
+        let vram = vec![0; WIDTH * HEIGHT];
+        let mut renderer = ScreenRenderer::new();
+        assert_eq!(renderer.size(&screen(&vram, false, false)), (WIDTH, HEIGHT));
+        renderer.native_lores = true;
+        assert_eq!(
+            renderer.size(&screen(&vram, false, false)),
+            (WIDTH / 2, HEIGHT / 2)
+        );
+    }
+
+    #[test]
+    fn hires_size() {
+        let vram = vec![0; WIDTH * HEIGHT];
+        let mut renderer = ScreenRenderer::new();
+        assert_eq!(renderer.size(&screen(&vram, true, false)), (WIDTH, HEIGHT));
+        renderer.native_lores = true;
+        assert_eq!(renderer.size(&screen(&vram, true, false)), (WIDTH, HEIGHT));
+    }
+
+    #[test]
+    fn hires64_size() {
+        let vram = vec![0; WIDTH * HEIGHT];
+        let mut renderer = ScreenRenderer::new();
+        renderer.native_lores = true;
+        let mut screen = screen(&vram, false, false);
+        screen.hires64 = true;
+        assert_eq!(renderer.size(&screen), (WIDTH / 2, HEIGHT));
+    }
+
+    #[test]
+    fn mega_size() {
+        let vram = vec![0; WIDTH * HEIGHT];
+        let mega = MegaChip::new();
+        let mut renderer = ScreenRenderer::new();
+        renderer.native_lores = true;
+        let mut screen = screen(&vram, false, false);
+        screen.mega = Some(&mega);
+        assert_eq!(renderer.size(&screen), (MEGA_WIDTH, MEGA_HEIGHT));
+    }
+
+    #[test]
+    fn render_applies_palette() {
+        let mut vram = vec![0; WIDTH * HEIGHT];
+        vram[..4].copy_from_slice(&[1, 2, 3, 0]);
+        let mut frame = vec![0; WIDTH * HEIGHT * 4];
+        ScreenRenderer::new().render(&screen(&vram, true, false), &test_palette(), &mut frame);
+        assert_eq!(
+            frame[..16],
+            [1, 2, 3, 0xff, 4, 5, 6, 0xff, 7, 8, 9, 0xff, 0, 0, 0, 0xff]
+        );
+    }
+
+    #[test]
+    fn render_applies_16_color_palette() {
+        let mut vram = vec![0; WIDTH * HEIGHT];
+        vram[..2].copy_from_slice(&[5, 15]);
+        let mut frame = vec![0; WIDTH * HEIGHT * 4];
+        ScreenRenderer::new().render(&screen(&vram, true, true), &test_palette(), &mut frame);
+        assert_eq!(frame[..8], [5, 5, 5, 0xff, 15, 15, 15, 0xff]);
+    }
+
+    #[test]
+    fn native_lores_render_takes_every_other_pixel() {
+        // Lores pixel (1, 1) is drawn doubled at vram (2, 2)
+        let mut vram = vec![0; WIDTH * HEIGHT];
+        vram[2 * WIDTH + 2] = 1;
+        let mut renderer = ScreenRenderer::new();
+        renderer.native_lores = true;
+        let mut frame = vec![0; WIDTH / 2 * HEIGHT / 2 * 4];
+        renderer.render(&screen(&vram, false, false), &test_palette(), &mut frame);
+        let lit = (WIDTH / 2 + 1) * 4;
+        assert_eq!(frame[lit..lit + 4], [1, 2, 3, 0xff]);
+        assert_eq!(frame.iter().filter(|&&ch| ch == 1).count(), 1);
+    }
+
+    #[test]
+    fn phosphor_fades_per_frame() {
+        let mut vram = vec![0; WIDTH * HEIGHT];
+        vram[0] = 1;
+        let mut renderer = ScreenRenderer::new();
+        renderer.filter = FrameFilter::Phosphor;
+        renderer.decay = 0.5;
+        renderer.advance_filter(&screen(&vram, true, false));
+        vram[0] = 0;
+        renderer.advance_filter(&screen(&vram, true, false));
+
+        // Redraws without a new frame show the same fade
+        let mut frame = vec![0; WIDTH * HEIGHT * 4];
+        for _ in 0..2 {
+            renderer.render_filtered(&screen(&vram, true, false), &test_palette(), &mut frame);
+            assert_eq!(frame[..4], [0, 1, 1, 0xff]);
+        }
+    }
+
+    #[test]
+    fn or_last_two_keeps_the_previous_frame() {
+        let mut vram = vec![0; WIDTH * HEIGHT];
+        vram[0] = 1;
+        let mut renderer = ScreenRenderer::new();
+        renderer.filter = FrameFilter::OrLastTwo;
+        renderer.advance_filter(&screen(&vram, true, false));
+        vram[0] = 2;
+        renderer.advance_filter(&screen(&vram, true, false));
+        let mut frame = vec![0; WIDTH * HEIGHT * 4];
+        renderer.render_filtered(&screen(&vram, true, false), &test_palette(), &mut frame);
+        assert_eq!(frame[..4], [7, 8, 9, 0xff]);
+
+        vram[0] = 0;
+        renderer.advance_filter(&screen(&vram, true, false));
+        renderer.render_filtered(&screen(&vram, true, false), &test_palette(), &mut frame);
+        assert_eq!(frame[..4], [4, 5, 6, 0xff]);
+    }
+
+    #[test]
+    fn mega_render_fades_by_alpha() {
+        let vram = vec![0; WIDTH * HEIGHT];
+        let mut mega = MegaChip::new();
+        mega.screen[..4].copy_from_slice(&[0xff, 0x80, 0, 0xff]);
+        mega.alpha = 0x80;
+        let mut screen = screen(&vram, false, false);
+        screen.mega = Some(&mega);
+        let mut frame = vec![0; MEGA_WIDTH * MEGA_HEIGHT * 4];
+        ScreenRenderer::new().render(&screen, &test_palette(), &mut frame);
+        assert_eq!(frame[..4], [0x80, 0x40, 0, 0xff]);
+    }
+}
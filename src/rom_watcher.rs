@@ -0,0 +1,93 @@
+use log::warn;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::ffi::OsString;
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver};
+use std::time::{Duration, Instant};
+
+// Editors often write a file in several steps, so wait for the events to settle
+const SETTLE_TIME: Duration = Duration::from_millis(200);
+
+pub struct RomWatcher {
+    watcher: Option<RecommendedWatcher>,
+    rx: Option<Receiver<notify::Result<notify::Event>>>,
+    fname: Option<OsString>,
+    pending_since: Option<Instant>,
+}
+
+impl RomWatcher {
+    pub fn new() -> Self {
+        Self {
+            watcher: None,
+            rx: None,
+            fname: None,
+            pending_since: None,
+        }
+    }
+
+    /// Start watching `path`, replacing any previously watched file.
+    pub fn watch(&mut self, path: &Path) {
+        self.unwatch();
+
+        // Watch the parent dir, as editors that save by renaming replace the file itself
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => Path::new(".").to_path_buf(),
+        };
+
+        let (tx, rx) = channel();
+        let mut watcher = match notify::recommended_watcher(tx) {
+            Ok(watcher) => watcher,
+            Err(err) => {
+                warn!("Couldn't create a file watcher: {}", err);
+                return;
+            }
+        };
+        if let Err(err) = watcher.watch(&dir, RecursiveMode::NonRecursive) {
+            warn!("Couldn't watch {}: {}", dir.to_string_lossy(), err);
+            return;
+        }
+
+        self.watcher = Some(watcher);
+        self.rx = Some(rx);
+        self.fname = path.file_name().map(|fname| fname.to_os_string());
+    }
+
+    pub fn unwatch(&mut self) {
+        self.watcher = None;
+        self.rx = None;
+        self.fname = None;
+        self.pending_since = None;
+    }
+
+    /// Returns true once the watched file has changed and settled.
+    pub fn changed(&mut self) -> bool {
+        let rx = match &self.rx {
+            Some(rx) => rx,
+            None => return false,
+        };
+
+        for res in rx.try_iter() {
+            let event = match res {
+                Ok(event) => event,
+                Err(_) => continue,
+            };
+            let is_write = matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_));
+            let is_rom = event
+                .paths
+                .iter()
+                .any(|path| path.file_name() == self.fname.as_deref());
+            if is_write && is_rom {
+                self.pending_since = Some(Instant::now());
+            }
+        }
+
+        match self.pending_since {
+            Some(since) if since.elapsed() >= SETTLE_TIME => {
+                self.pending_since = None;
+                true
+            }
+            _ => false,
+        }
+    }
+}
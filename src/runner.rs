@@ -0,0 +1,238 @@
+use crate::breakpoints::Breakpoints;
+use crate::chip8::Chip8;
+use crate::watchpoints::Watchpoints;
+
+/// A debugger command that runs until some point, then pauses.
+#[derive(Clone, Copy)]
+pub enum RunUntil {
+    /// Step over a `call`, until `pc` is back at `addr` with the stack at `sp`, so
+    /// recursion inside the call runs through.
+    Over { addr: u16, sp: u8 },
+    /// Step out, until a `return` pops the stack below `sp`.
+    Out { sp: u8 },
+    /// Until `pc` reaches the address, at any stack depth.
+    Cursor(u16),
+    /// For this many more instructions.
+    Count(u32),
+}
+
+impl RunUntil {
+    /// Run through the `call` at `pc`, or None if it's some other instruction, which
+    /// a plain step covers.
+    pub fn step_over(chip8: &Chip8) -> Option<Self> {
+        if chip8.halted || chip8.op_at(chip8.pc) >> 12 != 0x2 {
+            return None;
+        }
+        Some(RunUntil::Over {
+            addr: chip8.pc + 2,
+            sp: chip8.sp,
+        })
+    }
+
+    /// Run until the current subroutine returns, or None outside of any.
+    pub fn step_out(chip8: &Chip8) -> Option<Self> {
+        if chip8.sp == 0 {
+            return None;
+        }
+        Some(RunUntil::Out { sp: chip8.sp })
+    }
+
+    /// The address compiled blocks have to stop before.
+    fn addr(&self) -> Option<u16> {
+        match *self {
+            RunUntil::Over { addr, .. } | RunUntil::Cursor(addr) => Some(addr),
+            _ => None,
+        }
+    }
+
+    /// Counting needs instructions run one at a time.
+    fn needs_step(&self) -> bool {
+        matches!(self, RunUntil::Count(_))
+    }
+
+    /// Check after running an instruction or block. `executed` is false for the steps
+    /// spent waiting on a key for Fx0A, which don't count as instructions.
+    fn reached(&mut self, chip8: &Chip8, executed: bool) -> bool {
+        match self {
+            RunUntil::Over { addr, sp } => !chip8.halted && chip8.pc == *addr && chip8.sp == *sp,
+            RunUntil::Out { sp } => chip8.sp < *sp,
+            RunUntil::Cursor(addr) => !chip8.halted && chip8.pc == *addr,
+            RunUntil::Count(count) => {
+                if executed {
+                    *count = count.saturating_sub(1);
+                }
+                *count == 0
+            }
+        }
+    }
+}
+
+/// Run up to `ticks_left` instructions, stopping early at a breakpoint, watchpoint,
+/// `run_until` target or vblank wait. Pausing cancels `run_until`. Once the frame's
+/// instructions are used up, the timers are ticked, and `ticks_left` is refilled from
+/// `ins_per_frame`.
+/// Returns true if an emulated frame was completed, which it never is while paused.
+pub fn run_frame(
+    chip8: &mut Chip8,
+    breakpoints: &mut Breakpoints,
+    watchpoints: &mut Watchpoints,
+    run_until: &mut Option<RunUntil>,
+    ins_per_frame: i32,
+    ticks_left: &mut i32,
+) -> bool {
+    let running = !chip8.paused;
+    if chip8.paused {
+        *ticks_left = 0;
+        *run_until = None;
+    } else {
+        let mut ranges = breakpoints.ranges();
+        if let Some(addr) = run_until.as_ref().and_then(RunUntil::addr) {
+            ranges.push((addr, addr));
+        }
+        chip8.set_break_ranges(ranges);
+        while *ticks_left > 0 {
+            let was_halted = chip8.halted;
+            if watchpoints.is_active() || run_until.as_ref().is_some_and(RunUntil::needs_step) {
+                // Interpret one instruction at a time, so no access inside a compiled
+                // block is missed, and stop after the access so its new value is visible
+                let pc = chip8.pc;
+                let op = chip8.op_at(pc);
+                let accesses = chip8.check_mem_access();
+                let old: Vec<u8> = accesses
+                    .iter()
+                    .map(|&(addr, _)| chip8.mem[addr as usize])
+                    .collect();
+                let regs = watchpoints.snapshot_regs(chip8);
+                let old_vram = watchpoints.snapshot_draw(op, chip8);
+                chip8.step();
+                *ticks_left -= 1;
+                if watchpoints.check_mem_access(pc, op, &accesses, &old, &chip8.mem)
+                    || watchpoints.check_regs(pc, &regs, chip8)
+                    || watchpoints.check_draw(pc, op, old_vram.as_deref(), chip8)
+                {
+                    chip8.paused = true;
+                    *ticks_left = 0;
+                    break;
+                }
+            } else {
+                // No JIT
+                // chip8.step();
+                // *ticks_left -= 1;
+
+                // JIT
+                let cyc = chip8.run_block();
+                *ticks_left -= cyc;
+            }
+
+            if let Some(until) = run_until {
+                if until.reached(chip8, !was_halted) {
+                    *run_until = None;
+                    chip8.paused = true;
+                    *ticks_left = 0;
+                    break;
+                }
+            }
+
+            if chip8.halted {
+                *ticks_left = 0;
+                break;
+            }
+
+            if !chip8.halted && breakpoints.check(chip8) {
+                chip8.paused = true;
+                *ticks_left = 0;
+                break;
+            }
+
+            if chip8.wait_vblank {
+                chip8.wait_vblank = false;
+                *ticks_left = 0;
+                break;
+            }
+        }
+    }
+
+    if *ticks_left > 0 {
+        return false;
+    }
+
+    *ticks_left = ins_per_frame;
+    let regs = watchpoints.snapshot_regs(chip8);
+    if chip8.delay != 0 {
+        chip8.delay -= 1;
+    }
+    if chip8.sound != 0 {
+        chip8.sound -= 1;
+        if chip8.sound == 0 {
+            // todo: stop beep
+        }
+    }
+    if !chip8.paused && watchpoints.check_regs(chip8.pc, &regs, chip8) {
+        chip8.paused = true;
+    }
+    running
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Calls a subroutine at 0x204 that sets v0, then loops at 0x202.
+    fn calling() -> Chip8 {
+        let mut chip8 = Chip8::new();
+        chip8.load_rom(vec![0x22, 0x04, 0x12, 0x02, 0x60, 0x05, 0x00, 0xee]);
+        chip8
+    }
+
+    fn run_until(chip8: &mut Chip8, until: RunUntil) -> Option<RunUntil> {
+        chip8.paused = false;
+        chip8.wait_vblank = false;
+        let mut run_until = Some(until);
+        let mut ticks_left = 100;
+        run_frame(
+            chip8,
+            &mut Breakpoints::new(),
+            &mut Watchpoints::new(),
+            &mut run_until,
+            100,
+            &mut ticks_left,
+        );
+        run_until
+    }
+
+    #[test]
+    fn step_over_runs_through_the_call() {
+        let mut chip8 = calling();
+        let until = RunUntil::step_over(&chip8).unwrap();
+        assert!(run_until(&mut chip8, until).is_none());
+        assert!(chip8.paused);
+        assert_eq!((chip8.pc, chip8.sp, chip8.regs[0]), (0x202, 0, 5));
+    }
+
+    #[test]
+    fn step_over_is_only_for_calls() {
+        let mut chip8 = calling();
+        chip8.step();
+        assert!(RunUntil::step_over(&chip8).is_none());
+    }
+
+    #[test]
+    fn step_out_stops_after_the_return() {
+        let mut chip8 = calling();
+        assert!(RunUntil::step_out(&chip8).is_none());
+        chip8.step();
+        let until = RunUntil::step_out(&chip8).unwrap();
+        assert!(run_until(&mut chip8, until).is_none());
+        assert!(chip8.paused);
+        assert_eq!((chip8.pc, chip8.sp, chip8.regs[0]), (0x202, 0, 5));
+    }
+
+    #[test]
+    fn count_runs_that_many_instructions() {
+        let mut chip8 = Chip8::new();
+        chip8.load_rom([0x70, 0x01].repeat(8));
+        run_until(&mut chip8, RunUntil::Count(3));
+        assert!(chip8.paused);
+        assert_eq!(chip8.regs[0], 3);
+    }
+}
@@ -0,0 +1,262 @@
+use crate::breakpoints::{Breakpoint, Breakpoints};
+use crate::chip8::Chip8;
+use crate::constants::{APP_DIR_NAME, SETTINGS_FNAME};
+use crate::gui::OpenWindows;
+use crate::palette::Palette;
+use crate::profiles::Profile;
+use crate::watchpoints::{RegWatchpoint, VramWatchpoint, Watchpoint, Watchpoints};
+use crate::System;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+/// Settings that affect emulation, stored as defaults and per-ROM.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct EmulationSettings {
+    pub ins_per_frame: i32,
+    pub breakpoints: Vec<Breakpoint>,
+    pub watchpoints: Vec<Watchpoint>,
+    pub reg_watchpoints: Vec<RegWatchpoint>,
+    pub vram_watchpoints: Vec<VramWatchpoint>,
+    pub break_on_collision: bool,
+    pub palette: Palette,
+    pub profile: Profile,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub window_width: Option<f64>,
+    pub window_height: Option<f64>,
+    pub windows: OpenWindows,
+    pub hot_reload: bool,
+    pub hot_reload_keep_quirks: bool,
+    pub hot_reload_keep_debug: bool,
+    /// Used for ROMs without overrides.
+    pub emulation: EmulationSettings,
+
+    /// Overrides for a specific ROM, keyed by its hash. Only kept while they differ from
+    /// `emulation`.
+    pub roms: BTreeMap<String, EmulationSettings>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            window_width: None,
+            window_height: None,
+            windows: OpenWindows::default(),
+            hot_reload: true,
+            hot_reload_keep_quirks: true,
+            hot_reload_keep_debug: true,
+            emulation: EmulationSettings::default(),
+            roms: BTreeMap::new(),
+        }
+    }
+}
+
+/// Path of a file in the app's config dir, eg `~/.config/leina-chip8/` on Linux.
+pub fn config_path(fname: &str) -> PathBuf {
+    let dir = match dirs::config_dir() {
+        Some(dir) => dir.join(APP_DIR_NAME),
+        None => PathBuf::from("."),
+    };
+    if let Err(err) = fs::create_dir_all(&dir) {
+        warn!("Couldn't create {}: {}", dir.to_string_lossy(), err);
+    }
+    dir.join(fname)
+}
+
+/// Identifies a ROM independently of its file name.
+pub fn rom_hash(rom: &[u8]) -> String {
+    sha1_smol::Sha1::from(rom).digest().to_string()
+}
+
+impl Default for EmulationSettings {
+    /// What emulation starts as without any settings.
+    fn default() -> Self {
+        Self::capture(
+            &Chip8::new(),
+            &System::new(),
+            &Breakpoints::new(),
+            &Watchpoints::new(),
+        )
+    }
+}
+
+impl EmulationSettings {
+    pub fn capture(
+        chip8: &Chip8,
+        system: &System,
+        breakpoints: &Breakpoints,
+        watchpoints: &Watchpoints,
+    ) -> Self {
+        Self {
+            ins_per_frame: system.ins_per_frame,
+            breakpoints: breakpoints.breakpoints.clone(),
+            watchpoints: watchpoints.watchpoints.clone(),
+            reg_watchpoints: watchpoints.reg_watchpoints.clone(),
+            vram_watchpoints: watchpoints.vram_watchpoints.clone(),
+            break_on_collision: watchpoints.break_on_collision,
+            palette: system.palette.clone(),
+            profile: chip8.profile(),
+        }
+    }
+
+    pub fn apply(
+        &self,
+        chip8: &mut Chip8,
+        system: &mut System,
+        breakpoints: &mut Breakpoints,
+        watchpoints: &mut Watchpoints,
+    ) {
+        system.ins_per_frame = self.ins_per_frame;
+        chip8.set_profile(&self.profile);
+        breakpoints.breakpoints = self.breakpoints.clone();
+        watchpoints.watchpoints = self.watchpoints.clone();
+        watchpoints.reg_watchpoints = self.reg_watchpoints.clone();
+        watchpoints.vram_watchpoints = self.vram_watchpoints.clone();
+        watchpoints.break_on_collision = self.break_on_collision;
+        system.palette = self.palette.clone();
+    }
+}
+
+impl Settings {
+    /// Load the settings file, falling back to defaults if it's missing or invalid.
+    pub fn load() -> Self {
+        let path = config_path(SETTINGS_FNAME);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(_) => return Self::default(),
+        };
+        let mut settings: Settings = match toml::from_str(&contents) {
+            Ok(settings) => settings,
+            Err(err) => {
+                warn!("Ignoring invalid {}: {}", path.to_string_lossy(), err);
+                return Self::default();
+            }
+        };
+        settings.drop_invalid_profiles();
+        settings
+    }
+
+    /// Profiles are stored whole, so a hand-edited file can hold ones that can't run.
+    fn drop_invalid_profiles(&mut self) {
+        if let Err(err) = self.emulation.profile.validate() {
+            warn!("Ignoring the default profile: {}", err);
+            self.emulation.profile = Profile::new();
+        }
+        self.roms.retain(|hash, emulation| {
+            let result = emulation.profile.validate();
+            if let Err(err) = &result {
+                warn!("Ignoring the settings for ROM {}: {}", hash, err);
+            }
+            result.is_ok()
+        });
+    }
+
+    pub fn save(&self) {
+        let path = config_path(SETTINGS_FNAME);
+        let contents = match toml::to_string(self) {
+            Ok(contents) => contents,
+            Err(err) => {
+                warn!("Couldn't serialize settings: {}", err);
+                return;
+            }
+        };
+        if let Err(err) = fs::write(&path, contents) {
+            warn!("Couldn't save {}: {}", path.to_string_lossy(), err);
+        }
+    }
+
+    /// The emulation settings for a ROM: its overrides, or the defaults.
+    pub fn emulation_for(&self, rom_hash: &Option<String>) -> &EmulationSettings {
+        rom_hash
+            .as_ref()
+            .and_then(|hash| self.roms.get(hash))
+            .unwrap_or(&self.emulation)
+    }
+
+    /// Remember the current emulation settings, as overrides for the loaded ROM, or as
+    /// the defaults when there isn't one.
+    pub fn store_emulation(&mut self, emulation: EmulationSettings, rom_hash: &Option<String>) {
+        match rom_hash {
+            Some(hash) if emulation == self.emulation => {
+                self.roms.remove(hash);
+            }
+            Some(hash) => {
+                self.roms.insert(hash.clone(), emulation);
+            }
+            None => self.emulation = emulation,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::watchpoints::WatchTarget;
+
+    fn overrides() -> EmulationSettings {
+        EmulationSettings {
+            ins_per_frame: 15,
+            reg_watchpoints: vec![RegWatchpoint {
+                target: WatchTarget::Sp,
+                value: Some(3),
+            }],
+            break_on_collision: true,
+            profile: Profile::presets().remove(0),
+            ..EmulationSettings::default()
+        }
+    }
+
+    #[test]
+    fn overrides_round_trip() {
+        let mut settings = Settings::default();
+        let hash = Some(rom_hash(&[0x12, 0x00]));
+        settings.store_emulation(overrides(), &hash);
+
+        let contents = toml::to_string(&settings).unwrap();
+        let loaded: Settings = toml::from_str(&contents).unwrap();
+        assert!(*loaded.emulation_for(&hash) == overrides());
+        let other = Some(rom_hash(&[0x00, 0xe0]));
+        assert!(*loaded.emulation_for(&other) == EmulationSettings::default());
+    }
+
+    #[test]
+    fn only_differences_are_stored() {
+        let mut settings = Settings::default();
+        let hash = Some(rom_hash(&[0x12, 0x00]));
+        settings.store_emulation(EmulationSettings::default(), &hash);
+        assert!(settings.roms.is_empty());
+
+        settings.store_emulation(overrides(), &hash);
+        assert_eq!(settings.roms.len(), 1);
+        settings.store_emulation(EmulationSettings::default(), &hash);
+        assert!(settings.roms.is_empty());
+    }
+
+    #[test]
+    fn without_a_rom_the_defaults_are_stored() {
+        let mut settings = Settings::default();
+        settings.store_emulation(overrides(), &None);
+        assert!(settings.roms.is_empty());
+        assert!(*settings.emulation_for(&Some(rom_hash(&[]))) == overrides());
+    }
+
+    #[test]
+    fn invalid_profiles_are_dropped() {
+        let mut settings = Settings::default();
+        let mut emulation = overrides();
+        emulation.profile.mem_size = 0;
+        settings.store_emulation(emulation.clone(), &Some(rom_hash(&[0x12, 0x00])));
+        settings.store_emulation(emulation, &None);
+
+        settings.drop_invalid_profiles();
+        assert!(settings.roms.is_empty());
+        assert!(settings.emulation.profile == Profile::new());
+    }
+}
@@ -1,5 +1,11 @@
-use egui::Ui;
+use crate::chip8::Chip8;
+use crate::constants::{HEIGHT, MEGA_HEIGHT, MEGA_WIDTH, WIDTH};
 
+use egui::{DragValue, Ui};
+use serde::{Deserialize, Serialize};
+use std::cmp::min;
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct Watchpoint {
     pub addr_start: u16,
     pub addr_end: u16,
@@ -7,12 +13,164 @@ pub struct Watchpoint {
     pub write: bool,
 }
 
+/// Registers and other state that a `RegWatchpoint` can watch.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum WatchTarget {
+    VReg(u8),
+    I,
+    Delay,
+    Sound,
+    Hires,
+    Plane,
+    Sp,
+}
+
+impl WatchTarget {
+    pub fn all() -> Vec<WatchTarget> {
+        let mut ret: Vec<WatchTarget> = (0..16).map(WatchTarget::VReg).collect();
+        ret.extend([
+            WatchTarget::I,
+            WatchTarget::Delay,
+            WatchTarget::Sound,
+            WatchTarget::Hires,
+            WatchTarget::Plane,
+            WatchTarget::Sp,
+        ]);
+        ret
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            WatchTarget::VReg(reg) => format!("v{:x}", reg),
+            WatchTarget::I => String::from("i"),
+            WatchTarget::Delay => String::from("delay"),
+            WatchTarget::Sound => String::from("buzzer"),
+            WatchTarget::Hires => String::from("hires"),
+            WatchTarget::Plane => String::from("plane"),
+            WatchTarget::Sp => String::from("sp"),
+        }
+    }
+
+    pub fn read(&self, chip8: &Chip8) -> u32 {
+        match self {
+            WatchTarget::VReg(reg) => chip8.regs[*reg as usize] as u32,
+            WatchTarget::I => chip8.i_addr() as u32,
+            WatchTarget::Delay => chip8.delay as u32,
+            WatchTarget::Sound => chip8.sound as u32,
+            WatchTarget::Hires => chip8.hires as u32,
+            WatchTarget::Plane => chip8.plane as u32,
+            WatchTarget::Sp => chip8.sp as u32,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct RegWatchpoint {
+    pub target: WatchTarget,
+    /// Only break when the value changes to this, rather than on any change.
+    pub value: Option<u32>,
+}
+
+/// A rectangle of vram pixels, in hires coordinates, watched for drawing. With MEGA-CHIP
+/// enabled it's in MEGA-CHIP pixels instead, and any change of colour counts.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct VramWatchpoint {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+    /// Bits of the planes to watch, 1 for plane 1, 2 for plane 2 and so on.
+    pub planes: u8,
+}
+
+impl VramWatchpoint {
+    /// The first watched pixel that differs between `old` and what's drawn now.
+    fn changed_pixel(&self, old: &[u8], chip8: &Chip8) -> Option<(usize, usize)> {
+        let (new, width, height) = draw_buffer(chip8);
+        // Switching modes in between changes the buffer, and isn't a draw
+        if old.len() != new.len() {
+            return None;
+        }
+        // MEGA-CHIP pixels are palette indexes rather than planes
+        let planes = if chip8.mega.enabled {
+            0xff
+        } else {
+            self.planes
+        };
+        for y in self.y..min(self.y + self.height, height) {
+            for x in self.x..min(self.x + self.width, width) {
+                let i = y * width + x;
+                if (old[i] ^ new[i]) & planes != 0 {
+                    return Some((x, y));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// What draw instructions change, and its width and height: the MEGA-CHIP frame being
+/// drawn while that's enabled, otherwise vram.
+fn draw_buffer(chip8: &Chip8) -> (&[u8], usize, usize) {
+    if chip8.mega.enabled {
+        (chip8.mega.index(), MEGA_WIDTH, MEGA_HEIGHT)
+    } else {
+        (&chip8.vram, WIDTH, HEIGHT)
+    }
+}
+
+/// Whether `op` can change vram: `sprite`, `clear` and the scroll instructions,
+/// including MEGA-CHIP's scroll up.
+pub fn is_draw_op(op: u16) -> bool {
+    match op >> 12 {
+        0x0 => matches!(op, 0x00b0..=0x00df | 0x00e0 | 0x00fb | 0x00fc | 0x0230),
+        0xd => true,
+        _ => false,
+    }
+}
+
+/// What triggered a watchpoint.
+pub enum WatchpointHit {
+    Mem {
+        /// Address and opcode of the instruction that made the access.
+        pc: u16,
+        op: u16,
+        addr: u16,
+        is_read: bool,
+        old: u8,
+        new: u8,
+    },
+    Reg {
+        /// Where the change happened, or the next instruction if a timer ticked.
+        pc: u16,
+        target: WatchTarget,
+        old: u32,
+        new: u32,
+    },
+    Vram {
+        pc: u16,
+        op: u16,
+        x: usize,
+        y: usize,
+    },
+    /// A sprite set vF with collision breaking on.
+    Collision { pc: u16, op: u16 },
+}
+
 pub struct Watchpoints {
     addr_start: String,
     addr_end: String,
     read: bool,
     write: bool,
     pub watchpoints: Vec<Watchpoint>,
+    reg_target: WatchTarget,
+    reg_value: String,
+    pub reg_watchpoints: Vec<RegWatchpoint>,
+    vram_rect: [usize; 4],
+    vram_planes: u8,
+    pub vram_watchpoints: Vec<VramWatchpoint>,
+    pub break_on_collision: bool,
+    pub last_hit: Option<WatchpointHit>,
 }
 
 impl Watchpoints {
@@ -23,6 +181,14 @@ impl Watchpoints {
             read: false,
             write: false,
             watchpoints: vec![],
+            reg_target: WatchTarget::VReg(0),
+            reg_value: String::from(""),
+            reg_watchpoints: vec![],
+            vram_rect: [0, 0, WIDTH, HEIGHT],
+            vram_planes: 1,
+            vram_watchpoints: vec![],
+            break_on_collision: false,
+            last_hit: None,
         }
     }
 
@@ -63,6 +229,55 @@ impl Watchpoints {
             }
         }
 
+        match &self.last_hit {
+            Some(WatchpointHit::Mem {
+                pc,
+                op,
+                addr,
+                is_read,
+                old,
+                new,
+            }) => {
+                ui.separator();
+                let access = if *is_read {
+                    format!("read {:02x}", old)
+                } else {
+                    format!("wrote {:02x} -> {:02x}", old, new)
+                };
+                ui.label(format!(
+                    "Hit: {:04x} at {:03x} {} at {:04x}",
+                    op, pc, access, addr
+                ));
+            }
+            Some(WatchpointHit::Reg {
+                pc,
+                target,
+                old,
+                new,
+            }) => {
+                ui.separator();
+                ui.label(format!(
+                    "Hit: {} changed {:x} -> {:x} at {:03x}",
+                    target.name(),
+                    old,
+                    new,
+                    pc
+                ));
+            }
+            Some(WatchpointHit::Vram { pc, op, x, y }) => {
+                ui.separator();
+                ui.label(format!(
+                    "Hit: {:04x} at {:03x} drew at {}, {}",
+                    op, pc, x, y
+                ));
+            }
+            Some(WatchpointHit::Collision { pc, op }) => {
+                ui.separator();
+                ui.label(format!("Hit: {:04x} at {:03x} collided", op, pc));
+            }
+            None => (),
+        }
+
         if self.watchpoints.len() > 0 {
             ui.separator();
             let mut removed = None;
@@ -91,17 +306,206 @@ impl Watchpoints {
                 }
             }
         }
+
+        ui.separator();
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_label("Register")
+                .selected_text(self.reg_target.name())
+                .show_ui(ui, |ui| {
+                    for target in WatchTarget::all() {
+                        ui.selectable_value(&mut self.reg_target, target, target.name());
+                    }
+                });
+        });
+        ui.horizontal(|ui| {
+            let value_label = ui.label("Value:");
+            ui.text_edit_singleline(&mut self.reg_value)
+                .labelled_by(value_label.id)
+                .on_hover_text("Leave empty to break on any change");
+            self.reg_value.retain(|c| c.is_ascii_hexdigit());
+            if self.reg_value.len() > 6 {
+                self.reg_value = self.reg_value[..6].to_string();
+            }
+        });
+        if ui.button("Add register watchpoint").clicked() {
+            self.reg_watchpoints.push(RegWatchpoint {
+                target: self.reg_target,
+                value: u32::from_str_radix(&self.reg_value, 16).ok(),
+            });
+        }
+
+        if !self.reg_watchpoints.is_empty() {
+            ui.separator();
+            let mut removed = None;
+            for (i, watchpoint) in self.reg_watchpoints.iter().enumerate() {
+                ui.horizontal(|ui| {
+                    let condition = match watchpoint.value {
+                        Some(value) => format!("== {:x}", value),
+                        None => String::from("changes"),
+                    };
+                    ui.label(format!("{}: {} {}", i, watchpoint.target.name(), condition));
+                    if ui.button("Remove").clicked() {
+                        removed = Some(i);
+                    }
+                });
+            }
+            if let Some(idx) = removed {
+                self.reg_watchpoints.remove(idx);
+            }
+        }
+
+        ui.separator();
+        ui.horizontal(|ui| {
+            for (value, label) in self.vram_rect.iter_mut().zip(["X:", "Y:", "W:", "H:"]) {
+                ui.label(label);
+                ui.add(DragValue::new(value).clamp_range(0..=MEGA_WIDTH).speed(0.2));
+            }
+        });
+        ui.label("In MEGA-CHIP mode these are MEGA-CHIP pixels, and planes don't apply");
+        ui.horizontal(|ui| {
+            ui.label("Planes:");
+            for plane in 0..4 {
+                let mut watched = self.vram_planes & (1 << plane) != 0;
+                if ui
+                    .checkbox(&mut watched, format!("{}", plane + 1))
+                    .changed()
+                {
+                    self.vram_planes ^= 1 << plane;
+                }
+            }
+        });
+        if ui.button("Add VRAM watchpoint").clicked() && self.vram_planes != 0 {
+            let [x, y, width, height] = self.vram_rect;
+            self.vram_watchpoints.push(VramWatchpoint {
+                x,
+                y,
+                width,
+                height,
+                planes: self.vram_planes,
+            });
+        }
+        ui.checkbox(&mut self.break_on_collision, "Break on collision");
+
+        if !self.vram_watchpoints.is_empty() {
+            ui.separator();
+            let mut removed = None;
+            for (i, watchpoint) in self.vram_watchpoints.iter().enumerate() {
+                ui.horizontal(|ui| {
+                    ui.label(format!(
+                        "{}: {},{} {}x{} planes {:04b}",
+                        i,
+                        watchpoint.x,
+                        watchpoint.y,
+                        watchpoint.width,
+                        watchpoint.height,
+                        watchpoint.planes
+                    ));
+                    if ui.button("Remove").clicked() {
+                        removed = Some(i);
+                    }
+                });
+            }
+            if let Some(idx) = removed {
+                self.vram_watchpoints.remove(idx);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.watchpoints.clear();
+        self.reg_watchpoints.clear();
+        self.vram_watchpoints.clear();
+        self.last_hit = None;
+    }
+
+    /// Whether any watchpoints are set, which means instructions have to be interpreted.
+    pub fn is_active(&self) -> bool {
+        !self.watchpoints.is_empty()
+            || !self.reg_watchpoints.is_empty()
+            || !self.vram_watchpoints.is_empty()
+            || self.break_on_collision
+    }
+
+    /// What's drawn before `op` runs, if `check_draw` needs it to compare against.
+    pub fn snapshot_draw(&self, op: u16, chip8: &Chip8) -> Option<Vec<u8>> {
+        if self.vram_watchpoints.is_empty() || !is_draw_op(op) {
+            return None;
+        }
+        Some(draw_buffer(chip8).0.to_vec())
+    }
+
+    /// Check what the instruction `op` at `pc` drew, given what `snapshot_draw` returned
+    /// before it ran, and remember the first hit.
+    pub fn check_draw(&mut self, pc: u16, op: u16, old_vram: Option<&[u8]>, chip8: &Chip8) -> bool {
+        if let Some(old_vram) = old_vram {
+            for watchpoint in &self.vram_watchpoints {
+                if let Some((x, y)) = watchpoint.changed_pixel(old_vram, chip8) {
+                    self.last_hit = Some(WatchpointHit::Vram { pc, op, x, y });
+                    return true;
+                }
+            }
+        }
+        if self.break_on_collision && op >> 12 == 0xd && chip8.regs[0xf] != 0 {
+            self.last_hit = Some(WatchpointHit::Collision { pc, op });
+            return true;
+        }
+        false
+    }
+
+    /// The values watched by the register watchpoints, to pass to `check_regs` later.
+    pub fn snapshot_regs(&self, chip8: &Chip8) -> Vec<u32> {
+        self.reg_watchpoints
+            .iter()
+            .map(|watchpoint| watchpoint.target.read(chip8))
+            .collect()
+    }
+
+    /// Compare the watched values against a `snapshot_regs` from before the instruction
+    /// at `pc` ran, and remember the first hit.
+    pub fn check_regs(&mut self, pc: u16, before: &[u32], chip8: &Chip8) -> bool {
+        for (watchpoint, &old) in self.reg_watchpoints.iter().zip(before) {
+            let new = watchpoint.target.read(chip8);
+            let hit = match watchpoint.value {
+                Some(value) => new == value && old != value,
+                None => new != old,
+            };
+            if hit {
+                self.last_hit = Some(WatchpointHit::Reg {
+                    pc,
+                    target: watchpoint.target,
+                    old,
+                    new,
+                });
+                return true;
+            }
+        }
+        false
     }
 
-    pub fn check_mem_access(&mut self, accesses: Vec<(u16, bool)>) -> bool {
-        let mut hit_watchpoint = false;
-        for (addr, is_read) in accesses {
+    /// Check the accesses made by the instruction `op` at `pc`, given the bytes at each
+    /// address from before (`old`) and after (`mem`) it ran, and remember the first hit.
+    pub fn check_mem_access(
+        &mut self,
+        pc: u16,
+        op: u16,
+        accesses: &[(u16, bool)],
+        old: &[u8],
+        mem: &[u8],
+    ) -> bool {
+        for (&(addr, is_read), &old) in accesses.iter().zip(old) {
             if self.check(addr, is_read) {
-                hit_watchpoint = true;
-                break;
+                self.last_hit = Some(WatchpointHit::Mem {
+                    pc,
+                    op,
+                    addr,
+                    is_read,
+                    old,
+                    new: mem[addr as usize],
+                });
+                return true;
             }
         }
-        return hit_watchpoint;
+        false
     }
 
     fn check(&mut self, addr: u16, is_read: bool) -> bool {
@@ -119,3 +523,93 @@ impl Watchpoints {
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn watching(target: WatchTarget, value: Option<u32>) -> Watchpoints {
+        let mut watchpoints = Watchpoints::new();
+        watchpoints
+            .reg_watchpoints
+            .push(RegWatchpoint { target, value });
+        watchpoints
+    }
+
+    #[test]
+    fn reg_hit_reports_the_instruction_that_wrote() {
+        let mut watchpoints = watching(WatchTarget::VReg(0), None);
+        let mut chip8 = Chip8::new();
+        chip8.load_rom(vec![0x60, 0x05]);
+        let before = watchpoints.snapshot_regs(&chip8);
+        chip8.step();
+        assert!(watchpoints.check_regs(0x200, &before, &chip8));
+        match watchpoints.last_hit {
+            Some(WatchpointHit::Reg { pc, old, new, .. }) => {
+                assert_eq!((pc, old, new), (0x200, 0, 5))
+            }
+            _ => panic!("expected a register hit"),
+        }
+    }
+
+    #[test]
+    fn reg_value_only_hits_on_the_change_to_it() {
+        let mut watchpoints = watching(WatchTarget::I, Some(0x300));
+        let mut chip8 = Chip8::new();
+        chip8.load_rom(vec![0xa3, 0x00, 0xa3, 0x00, 0xa4, 0x00]);
+        let mut hits = vec![];
+        for _ in 0..3 {
+            let pc = chip8.pc;
+            let before = watchpoints.snapshot_regs(&chip8);
+            chip8.step();
+            hits.push(watchpoints.check_regs(pc, &before, &chip8));
+        }
+        assert_eq!(hits, [true, false, false]);
+    }
+
+    /// Draws the top left pixel twice, from a sprite at 0x206.
+    fn drawing_twice() -> Chip8 {
+        let mut chip8 = Chip8::new();
+        chip8.load_rom(vec![0xa2, 0x06, 0xd0, 0x01, 0xd0, 0x01, 0x80]);
+        chip8.step();
+        chip8
+    }
+
+    /// Step over the next instruction, returning whether it hit.
+    fn step_draw(watchpoints: &mut Watchpoints, chip8: &mut Chip8) -> bool {
+        let pc = chip8.pc;
+        let op = chip8.op_at(pc);
+        let old_vram = watchpoints.snapshot_draw(op, chip8);
+        chip8.step();
+        watchpoints.check_draw(pc, op, old_vram.as_deref(), chip8)
+    }
+
+    #[test]
+    fn vram_hits_only_inside_the_region() {
+        for (x, hit) in [(0, true), (8, false)] {
+            let mut watchpoints = Watchpoints::new();
+            watchpoints.vram_watchpoints.push(VramWatchpoint {
+                x,
+                y: 0,
+                width: 4,
+                height: 4,
+                planes: 1,
+            });
+            let mut chip8 = drawing_twice();
+            assert_eq!(step_draw(&mut watchpoints, &mut chip8), hit);
+        }
+    }
+
+    #[test]
+    fn collision_hits_on_the_second_draw() {
+        let mut watchpoints = Watchpoints::new();
+        watchpoints.break_on_collision = true;
+        let mut chip8 = drawing_twice();
+        assert!(!step_draw(&mut watchpoints, &mut chip8));
+        assert!(step_draw(&mut watchpoints, &mut chip8));
+        assert!(matches!(
+            watchpoints.last_hit,
+            Some(WatchpointHit::Collision { pc: 0x204, .. })
+        ));
+    }
+}